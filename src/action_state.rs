@@ -0,0 +1,84 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use bevy::prelude::*;
+
+use crate::{
+    input::{InputCommand, InputEvent},
+    replay::FrameTime,
+};
+
+#[derive(Clone, Copy, Debug, Default)]
+struct ActionData {
+    pressed: bool,
+    just_pressed: bool,
+    just_released: bool,
+    // Seconds of the current hold, keeps the last value after release until the next press
+    held: f32,
+}
+
+// Per frame state of every action, e.g. ActionState<InputCommand>
+pub struct ActionState<A> {
+    actions: HashMap<A, ActionData>,
+}
+
+impl<A> Default for ActionState<A> {
+    fn default() -> Self {
+        return Self {
+            actions: HashMap::new(),
+        };
+    }
+}
+
+impl<A: Copy + Eq + Hash> ActionState<A> {
+    // Advances the state by one frame given the actions that are down this frame
+    pub fn update(&mut self, pressed: &HashSet<A>, delta_seconds: f32) {
+        for action in pressed {
+            self.actions.entry(*action).or_default();
+        }
+        for (action, data) in self.actions.iter_mut() {
+            let is_pressed = pressed.contains(action);
+            data.just_pressed = is_pressed && !data.pressed;
+            data.just_released = !is_pressed && data.pressed;
+
+            if data.just_pressed {
+                data.held = 0.0;
+            } else if is_pressed {
+                data.held += delta_seconds;
+            }
+            data.pressed = is_pressed;
+        }
+    }
+
+    fn get(&self, action: A) -> ActionData {
+        self.actions.get(&action).copied().unwrap_or_default()
+    }
+
+    pub fn pressed(&self, action: A) -> bool {
+        self.get(action).pressed
+    }
+
+    pub fn just_pressed(&self, action: A) -> bool {
+        self.get(action).just_pressed
+    }
+
+    pub fn just_released(&self, action: A) -> bool {
+        self.get(action).just_released
+    }
+
+    // How long the action is held, or was held if it got released
+    pub fn held_duration(&self, action: A) -> f32 {
+        self.get(action).held
+    }
+}
+
+pub fn action_state_system(
+    frame_time: Res<FrameTime>,
+    mut input_events: EventReader<InputEvent>,
+    mut action_state: ResMut<ActionState<InputCommand>>,
+) {
+    let pressed: HashSet<InputCommand> = input_events.iter().map(|e| e.0).collect();
+    action_state.update(&pressed, frame_time.delta_seconds);
+}
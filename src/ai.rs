@@ -0,0 +1,337 @@
+use std::{collections::HashMap, fs};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    combat::Health,
+    movement::{facing_rotation, CharacterController, MovementIntent, MovementTarget},
+    navigation::NavAgent,
+    player::Player,
+    simulation::{SimulationStage, SimulationSystem, SIMULATION_TIMESTEP},
+    steering::{flee, SteeringAgent, Wander},
+};
+
+pub const AI_PROFILES_PATH: &str = "assets/ai_profiles.ron";
+
+// Chasing agents only request a new path when the player moved further than this
+const REPATH_DISTANCE: f32 = 0.5;
+const ARRIVAL_DISTANCE: f32 = 0.2;
+
+// Tuning values of one kind of enemy, loaded from AI_PROFILES_PATH
+#[derive(Clone, Debug, Deserialize)]
+pub struct AiProfile {
+    pub detection_radius: f32,
+    // Full angle of the vision cone in degrees
+    pub vision_angle: f32,
+    // The player is noticed this close regardless of the vision cone
+    pub hearing_radius: f32,
+    pub lose_interest_radius: f32,
+    // Agents never chase further than this from their home
+    pub leash_radius: f32,
+    pub attack_range: f32,
+    pub attack_cooldown: f32,
+    pub idle_time: f32,
+    // Relative to the home position, empty to stay idle at home
+    pub patrol_waypoints: Vec<Vec3>,
+    // Runs away from the player instead of chasing it
+    pub flees: bool,
+    // Also runs away once the health drops below this fraction of the maximum
+    #[serde(default)]
+    pub flee_health_fraction: f32,
+    pub flee_distance: f32,
+    // Wanders around while idle instead of standing still
+    #[serde(default)]
+    pub wanders: bool,
+}
+
+pub struct AiProfiles(pub HashMap<String, AiProfile>);
+
+impl AiProfiles {
+    pub fn load() -> AiProfiles {
+        let profiles = fs::read_to_string(AI_PROFILES_PATH)
+            .map_err(|e| e.to_string())
+            .and_then(|content| ron::from_str(&content).map_err(|e| e.to_string()));
+        match profiles {
+            Ok(profiles) => AiProfiles(profiles),
+            Err(e) => {
+                error!(
+                    "Failed to load AI profiles from {}: {}",
+                    AI_PROFILES_PATH, e
+                );
+                AiProfiles(HashMap::new())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum AiStateEnum {
+    IDLE,
+    PATROL,
+    CHASE,
+    ATTACK,
+    FLEE,
+    RETURN,
+}
+
+#[derive(Component)]
+pub struct AiController {
+    pub profile: String,
+    pub state: AiStateEnum,
+    // Where the agent patrols around and returns to, the spawn position if not set
+    pub home: Option<Vec3>,
+    state_time: f32,
+    patrol_index: usize,
+    attack_cooldown: f32,
+}
+
+impl AiController {
+    pub fn new(profile: &str) -> AiController {
+        return AiController {
+            profile: profile.to_string(),
+            state: AiStateEnum::IDLE,
+            home: None,
+            state_time: 0.0,
+            patrol_index: 0,
+            attack_cooldown: 0.0,
+        };
+    }
+}
+
+// Sent when an agent lands an attack, the combat code decides what it does
+#[derive(Clone, Copy, Debug)]
+pub struct AiAttackEvent {
+    pub attacker: Entity,
+    pub target: Entity,
+}
+
+fn planar(v: Vec3) -> Vec3 {
+    Vec3::new(v.x, 0.0, v.z)
+}
+
+fn detects(transform: &Transform, to_player: Vec3, profile: &AiProfile) -> bool {
+    let distance = to_player.length();
+    if distance <= profile.hearing_radius {
+        return true;
+    }
+    if distance > profile.detection_radius {
+        return false;
+    }
+    // Characters face +z
+    let forward = planar(transform.rotation * Vec3::Z);
+    forward.angle_between(to_player).to_degrees() <= profile.vision_angle / 2.0
+}
+
+fn set_destination(agent: &mut NavAgent, destination: Vec3) {
+    let repath = match agent.destination {
+        Some(current) => (current - destination).length() > REPATH_DISTANCE,
+        None => true,
+    };
+    if repath {
+        agent.destination = Some(destination);
+    }
+}
+
+fn stop(agent: &mut NavAgent, target: &mut MovementTarget, intent: &mut MovementIntent) {
+    agent.destination = None;
+    target.current_target = None;
+    target.waypoints.clear();
+    intent.direction = Vec3::ZERO;
+}
+
+pub fn ai_system(
+    profiles: Res<AiProfiles>,
+    players: Query<(Entity, &Transform), (With<Player>, Without<AiController>)>,
+    mut agents: Query<(
+        Entity,
+        &Transform,
+        &mut AiController,
+        &mut NavAgent,
+        &mut MovementTarget,
+        &mut MovementIntent,
+        &mut CharacterController,
+        Option<&Health>,
+        Option<&mut SteeringAgent>,
+    )>,
+    mut attack_events: EventWriter<AiAttackEvent>,
+) {
+    let player = players.get_single().ok();
+
+    for (
+        entity,
+        transform,
+        mut ai,
+        mut agent,
+        mut target,
+        mut intent,
+        mut controller,
+        health,
+        steering,
+    ) in agents.iter_mut()
+    {
+        let profile = match profiles.0.get(&ai.profile) {
+            Some(profile) => profile,
+            None => continue,
+        };
+        let position = transform.translation;
+        let home = *ai.home.get_or_insert(position);
+        ai.state_time += SIMULATION_TIMESTEP;
+        ai.attack_cooldown -= SIMULATION_TIMESTEP;
+
+        let to_player = player.map(|(_, p)| planar(p.translation - position));
+        let player_distance = to_player.map(|v| v.length()).unwrap_or(f32::INFINITY);
+        let sees_player = to_player
+            .map(|v| detects(transform, v, profile))
+            .unwrap_or(false);
+        let home_distance = planar(home - position).length();
+        let wounded = health
+            .map(|h| h.current < h.max * profile.flee_health_fraction)
+            .unwrap_or(false);
+        let engage = if profile.flees || wounded {
+            AiStateEnum::FLEE
+        } else {
+            AiStateEnum::CHASE
+        };
+        let patrol_point = if profile.patrol_waypoints.is_empty() {
+            home
+        } else {
+            home + profile.patrol_waypoints[ai.patrol_index % profile.patrol_waypoints.len()]
+        };
+        let arrived_at = |point: Vec3| planar(point - position).length() <= ARRIVAL_DISTANCE;
+
+        let current_state = ai.state;
+        let next_state = match current_state {
+            AiStateEnum::IDLE | AiStateEnum::PATROL if sees_player => engage,
+            // Wandered or got pushed too far away
+            AiStateEnum::IDLE if home_distance > profile.leash_radius => AiStateEnum::RETURN,
+            AiStateEnum::IDLE => {
+                if ai.state_time >= profile.idle_time && !profile.patrol_waypoints.is_empty() {
+                    AiStateEnum::PATROL
+                } else {
+                    AiStateEnum::IDLE
+                }
+            }
+            AiStateEnum::PATROL => {
+                if arrived_at(patrol_point) {
+                    ai.patrol_index = (ai.patrol_index + 1) % profile.patrol_waypoints.len().max(1);
+                    AiStateEnum::IDLE
+                } else {
+                    AiStateEnum::PATROL
+                }
+            }
+            AiStateEnum::CHASE | AiStateEnum::ATTACK if wounded => AiStateEnum::FLEE,
+            AiStateEnum::CHASE => {
+                if player_distance > profile.lose_interest_radius
+                    || home_distance > profile.leash_radius
+                {
+                    AiStateEnum::RETURN
+                } else if player_distance <= profile.attack_range {
+                    AiStateEnum::ATTACK
+                } else {
+                    AiStateEnum::CHASE
+                }
+            }
+            AiStateEnum::ATTACK => {
+                if player_distance > profile.attack_range * 1.2 {
+                    AiStateEnum::CHASE
+                } else {
+                    AiStateEnum::ATTACK
+                }
+            }
+            AiStateEnum::FLEE => {
+                if player_distance > profile.flee_distance {
+                    AiStateEnum::RETURN
+                } else {
+                    AiStateEnum::FLEE
+                }
+            }
+            AiStateEnum::RETURN => {
+                if sees_player && home_distance < profile.leash_radius {
+                    engage
+                } else if arrived_at(home) {
+                    AiStateEnum::IDLE
+                } else {
+                    AiStateEnum::RETURN
+                }
+            }
+        };
+
+        if next_state != ai.state {
+            ai.state = next_state;
+            ai.state_time = 0.0;
+        }
+
+        if let Some(mut steering) = steering {
+            let wanders = profile.wanders && ai.state == AiStateEnum::IDLE;
+            if wanders && steering.wander.is_none() {
+                // Seeded by the entity so every agent walks its own way
+                steering.wander = Some(Wander::new(entity.id()));
+            } else if !wanders && steering.wander.is_some() {
+                steering.wander = None;
+            }
+        }
+
+        match ai.state {
+            AiStateEnum::IDLE => {
+                if agent.destination.is_some() {
+                    stop(&mut agent, &mut target, &mut intent);
+                }
+            }
+            AiStateEnum::PATROL => set_destination(&mut agent, patrol_point),
+            AiStateEnum::CHASE => {
+                if let Some((_, player_transform)) = player {
+                    set_destination(&mut agent, player_transform.translation);
+                }
+            }
+            AiStateEnum::ATTACK => {
+                if agent.destination.is_some() {
+                    stop(&mut agent, &mut target, &mut intent);
+                }
+                if let (Some((player_entity, _)), Some(to_player)) = (player, to_player) {
+                    if to_player.length() > 0.0 {
+                        controller.target_rotation = facing_rotation(to_player);
+                    }
+                    if ai.attack_cooldown <= 0.0 {
+                        ai.attack_cooldown = profile.attack_cooldown;
+                        attack_events.send(AiAttackEvent {
+                            attacker: entity,
+                            target: player_entity,
+                        });
+                    }
+                }
+            }
+            AiStateEnum::FLEE => {
+                if let Some((_, player_transform)) = player {
+                    let away = flee(
+                        position,
+                        player_transform.translation,
+                        profile.flee_distance,
+                    );
+                    set_destination(&mut agent, position + away);
+                }
+            }
+            AiStateEnum::RETURN => set_destination(&mut agent, home),
+        }
+    }
+}
+
+pub struct AiPlugin;
+
+impl Plugin for AiPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(AiProfiles::load())
+            .add_event::<AiAttackEvent>()
+            .add_system_to_stage(
+                SimulationStage,
+                ai_system
+                    .label(SimulationSystem::AI)
+                    .after(SimulationSystem::PREPARE)
+                    .before(SimulationSystem::INTENT),
+            );
+    }
+
+    fn name(&self) -> &str {
+        "AiPlugin"
+    }
+}
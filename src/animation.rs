@@ -1,50 +1,444 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
-use bevy::prelude::*;
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    gltf::Gltf,
+    prelude::*,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use serde::Deserialize;
 
-use crate::player::{PlayerState, PlayerStateEnum};
+use crate::{app_state::in_game, scene_ready::SceneReadyEvent};
 
-pub struct Animations(pub Vec<Handle<AnimationClip>>);
+// A state machine over the animations of a glTF file, loaded from *.animgraph files
+#[derive(Debug, Deserialize, TypeUuid)]
+#[uuid = "5b1c2a0e-8f0d-4c39-a1e5-6a7d2c94b3f1"]
+pub struct AnimationGraph {
+    pub initial_state: String,
+    pub states: HashMap<String, AnimationGraphNode>,
+    // Checked in order, the first one whose conditions hold is taken
+    pub transitions: Vec<AnimationTransition>,
+    // Events sent when a clip passes the timestamps, keyed by clip name
+    #[serde(default)]
+    pub notifies: HashMap<String, Vec<AnimationNotify>>,
+    // Bone whose motion is moved to the character in root motion states
+    #[serde(default = "default_root_bone")]
+    pub root_bone: String,
+}
+
+fn default_root_bone() -> String {
+    "root".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AnimationNotify {
+    // Seconds into the clip
+    pub time: f32,
+    pub name: String,
+}
+
+// Sent when the animation of a character passes a notify of the playing clip
+#[derive(Clone, Debug)]
+pub struct AnimationEvent {
+    // The character root, not the AnimationPlayer entity
+    pub entity: Entity,
+    pub clip: String,
+    pub name: String,
+}
+
+// How often the time of a notify was passed between two elapsed times of the player,
+// looping clips pass it once per loop
+fn notify_crossings(time: f32, from: f32, to: f32, duration: f32, looping: bool) -> usize {
+    if !looping || duration <= 0.0 {
+        return if from < time && time <= to { 1 } else { 0 };
+    }
+    let first = (((from - time) / duration).floor() + 1.0).max(0.0);
+    let last = ((to - time) / duration).floor();
+    if last < first {
+        0
+    } else {
+        (last - first) as usize + 1
+    }
+}
+
+// Playback speed of blend space samples is kept in this range of the authored speed
+const MIN_BLEND_PLAYBACK_SPEED: f32 = 0.2;
+const MAX_BLEND_PLAYBACK_SPEED: f32 = 3.0;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AnimationGraphNode {
+    // Name of the animation in the glTF file, unused if the node is a blend space
+    #[serde(default)]
+    pub clip: String,
+    #[serde(default)]
+    pub blend_space: Option<BlendSpace>,
+    // Multiplies the playback speed, also of blend space samples
+    #[serde(default = "default_speed")]
+    pub speed: f32,
+    #[serde(default = "default_looping")]
+    pub looping: bool,
+    // Moves the character by the horizontal motion of the root bone instead of its controller
+    #[serde(default)]
+    pub root_motion: bool,
+}
+
+// Picks the sample closest to the current parameters and scales its playback speed so the
+// motion of the clip matches them. The animation player can only fade between two clips,
+// so samples are not weighted against each other
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlendSpace {
+    // Parameter of the horizontal axis, a 1D blend space if not set
+    #[serde(default)]
+    pub x: Option<String>,
+    pub y: String,
+    pub samples: Vec<BlendSample>,
+    // Seconds to fade over when the closest sample changes
+    pub blend_time: f32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlendSample {
+    pub clip: String,
+    // Parameter values the clip was authored at, e.g. the local velocity of a walk cycle
+    pub position: Vec2,
+}
+
+impl BlendSpace {
+    // The clip to play and its playback speed relative to the authored one
+    fn sample(&self, parameters: &AnimationParameters) -> Option<(&str, f32)> {
+        let x = self.x.as_ref().map_or(0.0, |x| parameters.get(x));
+        let point = Vec2::new(x, parameters.get(&self.y));
+        let sample = self.samples.iter().min_by(|a, b| {
+            a.position
+                .distance_squared(point)
+                .total_cmp(&b.position.distance_squared(point))
+        })?;
+        let authored = sample.position.length();
+        let speed = if authored > 0.0 {
+            (point.length() / authored).clamp(MIN_BLEND_PLAYBACK_SPEED, MAX_BLEND_PLAYBACK_SPEED)
+        } else {
+            1.0
+        };
+        Some((&sample.clip, speed))
+    }
+}
+
+impl AnimationGraphNode {
+    // The clip to play and its playback speed for the current parameters
+    fn resolve(&self, parameters: &AnimationParameters) -> Option<(&str, f32)> {
+        match &self.blend_space {
+            Some(blend_space) => blend_space
+                .sample(parameters)
+                .map(|(clip, speed)| (clip, speed * self.speed)),
+            None => Some((&self.clip, self.speed)),
+        }
+    }
+}
+
+fn default_speed() -> f32 {
+    1.0
+}
+
+fn default_looping() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AnimationTransition {
+    // Taken from any state if not set
+    #[serde(default)]
+    pub from: Option<String>,
+    pub to: String,
+    pub conditions: Vec<AnimationCondition>,
+    // Seconds the previous animation is faded out over
+    pub blend_time: f32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub enum AnimationCondition {
+    GREATER(String, f32),
+    LESS(String, f32),
+    // The parameter is not zero
+    TRUE(String),
+    FALSE(String),
+    // The current state doesn't loop and played to its end
+    FINISHED,
+}
+
+impl AnimationGraph {
+    fn transition_from(
+        &self,
+        state: &str,
+        parameters: &AnimationParameters,
+        finished: bool,
+    ) -> Option<&AnimationTransition> {
+        self.transitions.iter().find(|t| {
+            t.to != state
+                && t.from.as_deref().map_or(true, |from| from == state)
+                && t.conditions.iter().all(|c| match c {
+                    AnimationCondition::GREATER(name, value) => parameters.get(name) > *value,
+                    AnimationCondition::LESS(name, value) => parameters.get(name) < *value,
+                    AnimationCondition::TRUE(name) => parameters.get(name) != 0.0,
+                    AnimationCondition::FALSE(name) => parameters.get(name) == 0.0,
+                    AnimationCondition::FINISHED => finished,
+                })
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct AnimationGraphLoader;
+
+impl AssetLoader for AnimationGraphLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let graph: AnimationGraph = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(graph));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["animgraph"]
+    }
+}
+
+// Values the transition conditions are checked against, written by gameplay systems
+#[derive(Component, Default)]
+pub struct AnimationParameters(pub HashMap<String, f32>);
+
+impl AnimationParameters {
+    pub fn get(&self, name: &str) -> f32 {
+        self.0.get(name).copied().unwrap_or(0.0)
+    }
+
+    pub fn set(&mut self, name: &str, value: f32) {
+        self.0.insert(name.to_string(), value);
+    }
+
+    pub fn set_bool(&mut self, name: &str, value: bool) {
+        self.set(name, if value { 1.0 } else { 0.0 });
+    }
+}
+
+// Current state of the graph, lives on the character root
+#[derive(Component)]
+pub struct AnimationGraphState {
+    pub current: String,
+    // Clip the state is playing, changes within blend spaces
+    pub clip: String,
+    // Elapsed time of the player when notifies were last checked, not set after the clip started
+    pub notify_time: Option<f32>,
+}
 
-pub fn player_animation_system(
-    animations: Res<Animations>,
-    mut player_query: Query<(&Parent, &mut AnimationPlayer)>,
-    mut state_query: Query<&mut PlayerState>,
+// The glTF file the clips are looked up in and the graph driving them, per character
+#[derive(Component, Default)]
+pub struct Animations {
+    pub gltf: Handle<Gltf>,
+    pub graph: Handle<AnimationGraph>,
+}
+
+// The AnimationPlayer somewhere in the spawned scene of the character
+#[derive(Component)]
+pub struct AnimationPlayerLink(pub Entity);
+
+fn find_animation_player(
+    entity: Entity,
+    children_query: &Query<&Children>,
+    player_query: &Query<(), With<AnimationPlayer>>,
+) -> Option<Entity> {
+    if player_query.get(entity).is_ok() {
+        return Some(entity);
+    }
+    children_query
+        .get(entity)
+        .ok()?
+        .iter()
+        .find_map(|child| find_animation_player(*child, children_query, player_query))
+}
+
+// Links characters to their AnimationPlayer once their scene got spawned
+pub fn animation_player_link_system(
+    mut commands: Commands,
+    mut ready_events: EventReader<SceneReadyEvent>,
+    query: Query<(), With<Animations>>,
+    children_query: Query<&Children>,
+    player_query: Query<(), With<AnimationPlayer>>,
 ) {
-    let idle_index = 0;
-    let run_index = 1;
-
-    for (parent, mut player) in player_query.iter_mut() {
-        if let Ok(mut state) = state_query.get_single_mut() {
-            match state.state {
-                PlayerStateEnum::IDLE => {
-                    if state.animation.is_none() || state.animation.unwrap() != idle_index {
-                        if state.animation.is_none() {
-                            player.play(animations.0[idle_index].clone_weak()).repeat();
-                        } else {
-                            player
-                                .cross_fade(
-                                    animations.0[idle_index].clone_weak(),
-                                    Duration::from_secs_f32(0.3),
-                                )
-                                .repeat();
-                        }
-                        state.animation = Some(idle_index);
+    for e in ready_events.iter() {
+        if query.get(e.root).is_err() {
+            continue;
+        }
+        match find_animation_player(e.root, &children_query, &player_query) {
+            Some(player) => {
+                commands.entity(e.root).insert(AnimationPlayerLink(player));
+            }
+            None => warn!("No AnimationPlayer in the scene of {:?}", e.root),
+        }
+    }
+}
+
+// Starts the clip on the player, cross fading from the previous animation if a blend time is given
+fn play_clip(
+    player: &mut AnimationPlayer,
+    gltf: &Gltf,
+    name: &str,
+    looping: bool,
+    blend_time: Option<f32>,
+) -> bool {
+    let clip = match gltf.named_animations.get(name) {
+        Some(clip) => clip.clone_weak(),
+        None => {
+            warn!("Animation clip {} not found", name);
+            return false;
+        }
+    };
+    let player = match blend_time {
+        Some(blend_time) => player.cross_fade(clip, Duration::from_secs_f32(blend_time)),
+        None => player.play(clip),
+    };
+    if looping {
+        player.repeat();
+    } else {
+        player.stop_repeating();
+    }
+    true
+}
+
+pub fn animation_graph_system(
+    mut commands: Commands,
+    graphs: Res<Assets<AnimationGraph>>,
+    gltfs: Res<Assets<Gltf>>,
+    clips: Res<Assets<AnimationClip>>,
+    mut character_query: Query<(
+        Entity,
+        &Animations,
+        &AnimationPlayerLink,
+        Option<&AnimationParameters>,
+        Option<&mut AnimationGraphState>,
+    )>,
+    mut player_query: Query<&mut AnimationPlayer>,
+    mut animation_events: EventWriter<AnimationEvent>,
+) {
+    let no_parameters = AnimationParameters::default();
+
+    for (entity, animations, link, parameters, state) in character_query.iter_mut() {
+        let (graph, gltf) = match (graphs.get(&animations.graph), gltfs.get(&animations.gltf)) {
+            (Some(graph), Some(gltf)) => (graph, gltf),
+            _ => continue,
+        };
+        let mut player = match player_query.get_mut(link.0) {
+            Ok(player) => player,
+            Err(_) => continue,
+        };
+        let parameters = parameters.unwrap_or(&no_parameters);
+
+        let mut state = match state {
+            Some(state) => state,
+            None => {
+                let node = match graph.states.get(&graph.initial_state) {
+                    Some(node) => node,
+                    None => {
+                        warn!("Animation state {} not found", graph.initial_state);
+                        continue;
                     }
-                }
-                PlayerStateEnum::MOVING => {
-                    if state.animation.is_none() || state.animation.unwrap() != run_index {
-                        player
-                            .cross_fade(
-                                animations.0[run_index].clone_weak(),
-                                Duration::from_secs_f32(0.25),
-                            )
-                            .set_speed(1.3)
-                            .repeat();
-                        state.animation = Some(run_index);
+                };
+                if let Some((clip, speed)) = node.resolve(parameters) {
+                    if play_clip(&mut player, gltf, clip, node.looping, None) {
+                        player.set_speed(speed);
+                        commands.entity(entity).insert(AnimationGraphState {
+                            current: graph.initial_state.clone(),
+                            clip: clip.to_string(),
+                            notify_time: None,
+                        });
                     }
                 }
-            };
+                continue;
+            }
+        };
+
+        let finished = graph
+            .states
+            .get(&state.current)
+            .filter(|node| !node.looping)
+            .and_then(|_| gltf.named_animations.get(&state.clip))
+            .and_then(|clip| clips.get(clip))
+            .map_or(false, |clip| player.elapsed() >= clip.duration());
+
+        // Either enter the next state or keep the clip and speed of the current one up to date
+        let (next, blend_time) = match graph.transition_from(&state.current, parameters, finished) {
+            Some(transition) => (transition.to.clone(), Some(transition.blend_time)),
+            None => (state.current.clone(), None),
+        };
+        let node = match graph.states.get(&next) {
+            Some(node) => node,
+            None => {
+                warn!("Animation state {} not found", next);
+                continue;
+            }
+        };
+        let (clip, speed) = match node.resolve(parameters) {
+            Some(resolved) => resolved,
+            None => continue,
+        };
+        let blend_time = blend_time.or_else(|| {
+            node.blend_space
+                .as_ref()
+                .filter(|_| clip != state.clip)
+                .map(|blend_space| blend_space.blend_time)
+        });
+        if let Some(blend_time) = blend_time {
+            if !play_clip(&mut player, gltf, clip, node.looping, Some(blend_time)) {
+                continue;
+            }
+            state.current = next;
+            if clip != state.clip {
+                state.clip = clip.to_string();
+                state.notify_time = None;
+            }
+        }
+        player.set_speed(speed);
+
+        // Notifies right at the start of the clip are sent as well
+        let from = state.notify_time.unwrap_or(-f32::EPSILON);
+        let to = player.elapsed();
+        if let Some(notifies) = graph.notifies.get(&state.clip) {
+            let duration = gltf
+                .named_animations
+                .get(&state.clip)
+                .and_then(|clip| clips.get(clip))
+                .map_or(0.0, |clip| clip.duration());
+            for notify in notifies.iter() {
+                for _ in 0..notify_crossings(notify.time, from, to, duration, node.looping) {
+                    animation_events.send(AnimationEvent {
+                        entity,
+                        clip: state.clip.clone(),
+                        name: notify.name.clone(),
+                    });
+                }
+            }
         }
+        state.notify_time = Some(to);
+    }
+}
+
+pub struct AnimationGraphPlugin;
+
+impl Plugin for AnimationGraphPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<AnimationGraph>()
+            .add_event::<AnimationEvent>()
+            .init_asset_loader::<AnimationGraphLoader>()
+            .add_system(animation_player_link_system.before(animation_graph_system))
+            .add_system(animation_graph_system.with_run_criteria(in_game));
+    }
+
+    fn name(&self) -> &str {
+        "AnimationGraphPlugin"
     }
 }
@@ -0,0 +1,430 @@
+use bevy::{app::AppExit, ecs::schedule::ShouldRun, prelude::*};
+
+use crate::{
+    action_state::ActionState,
+    asset_manifest::{
+        asset_manifest_load_system, asset_manifest_progress_system, AssetLoadProgress,
+    },
+    combat::DeathEvent,
+    input::InputCommand,
+    input_map::{rebind_system, InputMap, PendingRebind, RebindEvent},
+    player::Player,
+    simulation::{SimulationClock, SimulationStage, SimulationSystem},
+};
+
+// Pause menu entries for rebinding
+const REBIND_KEYS: [(KeyCode, InputCommand); 5] = [
+    (KeyCode::Key1, InputCommand::LEFT),
+    (KeyCode::Key2, InputCommand::RIGHT),
+    (KeyCode::Key3, InputCommand::UP),
+    (KeyCode::Key4, InputCommand::DOWN),
+    (KeyCode::Key5, InputCommand::ACTION),
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum AppState {
+    BOOT,
+    LOADING,
+    MENU,
+    // The level exists and the simulation runs
+    PLAYING,
+    // Pushed on top of PLAYING, the level stays
+    PAUSED,
+    GAMEOVER,
+}
+
+// Run criteria for gameplay systems outside of the simulation stage
+pub fn in_game(state: Option<Res<State<AppState>>>) -> ShouldRun {
+    match state {
+        Some(state) if *state.current() != AppState::PLAYING => ShouldRun::No,
+        _ => ShouldRun::Yes,
+    }
+}
+
+// UI of a single state, despawned when the state is left
+#[derive(Component)]
+pub struct StateUi;
+
+// Only exists while no level camera renders the UI
+#[derive(Component)]
+pub struct MenuCamera;
+
+#[derive(Component)]
+struct LoadingBar;
+
+fn boot_system(mut app_state: ResMut<State<AppState>>) {
+    if let Err(e) = app_state.set(AppState::LOADING) {
+        warn!("Failed to leave boot: {:?}", e);
+    }
+}
+
+fn loading_ui_spawn_system(mut commands: Commands) {
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            color: Color::BLACK.into(),
+            ..default()
+        })
+        .insert(StateUi)
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        size: Size::new(Val::Percent(50.0), Val::Px(16.0)),
+                        ..default()
+                    },
+                    color: Color::rgb(0.15, 0.15, 0.15).into(),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    parent
+                        .spawn_bundle(NodeBundle {
+                            style: Style {
+                                size: Size::new(Val::Percent(0.0), Val::Percent(100.0)),
+                                ..default()
+                            },
+                            color: Color::rgb(0.0, 0.8, 1.0).into(),
+                            ..default()
+                        })
+                        .insert(LoadingBar);
+                });
+        });
+}
+
+// Shows the progress of the level assets and moves on once all of them finished
+fn loading_progress_system(
+    progress: Option<Res<AssetLoadProgress>>,
+    mut app_state: ResMut<State<AppState>>,
+    mut bar_query: Query<&mut Style, With<LoadingBar>>,
+) {
+    let progress = match progress {
+        Some(progress) => progress,
+        None => return,
+    };
+    for mut style in bar_query.iter_mut() {
+        style.size.width = Val::Percent(progress.fraction() * 100.0);
+    }
+
+    if progress.finished() {
+        if !progress.failed.is_empty() {
+            warn!(
+                "{} level assets failed to load: {:?}",
+                progress.failed.len(),
+                progress.failed
+            );
+        }
+        if let Err(e) = app_state.set(AppState::MENU) {
+            warn!("Failed to leave loading: {:?}", e);
+        }
+    }
+}
+
+fn menu_ui_spawn_system(mut commands: Commands) {
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            color: Color::rgb(0.02, 0.02, 0.05).into(),
+            ..default()
+        })
+        .insert(StateUi)
+        .with_children(|parent| {
+            // Title bar
+            parent.spawn_bundle(NodeBundle {
+                style: Style {
+                    size: Size::new(Val::Px(320.0), Val::Px(24.0)),
+                    margin: UiRect::all(Val::Px(24.0)),
+                    ..default()
+                },
+                color: Color::rgb(0.0, 0.8, 1.0).into(),
+                ..default()
+            });
+            // Start, triggered with the action input or enter
+            parent.spawn_bundle(NodeBundle {
+                style: Style {
+                    size: Size::new(Val::Px(64.0), Val::Px(64.0)),
+                    ..default()
+                },
+                color: Color::rgb(0.9, 0.9, 0.9).into(),
+                ..default()
+            });
+        });
+}
+
+fn overlay(commands: &mut Commands, color: Color, pause_symbol: bool) {
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                position_type: PositionType::Absolute,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            color: color.into(),
+            ..default()
+        })
+        .insert(StateUi)
+        .with_children(|parent| {
+            if !pause_symbol {
+                return;
+            }
+            // Two bars, the usual pause symbol
+            for _ in 0..2 {
+                parent.spawn_bundle(NodeBundle {
+                    style: Style {
+                        size: Size::new(Val::Px(24.0), Val::Px(80.0)),
+                        margin: UiRect::all(Val::Px(12.0)),
+                        ..default()
+                    },
+                    color: Color::rgba(1.0, 1.0, 1.0, 0.8).into(),
+                    ..default()
+                });
+            }
+        });
+}
+
+fn pause_ui_spawn_system(mut commands: Commands) {
+    overlay(&mut commands, Color::rgba(0.0, 0.0, 0.0, 0.6), true);
+}
+
+fn game_over_ui_spawn_system(mut commands: Commands) {
+    overlay(&mut commands, Color::rgb(0.3, 0.0, 0.0), false);
+}
+
+fn state_ui_despawn_system(mut commands: Commands, query: Query<Entity, With<StateUi>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn confirm_pressed(keys: &Input<KeyCode>, action_state: &ActionState<InputCommand>) -> bool {
+    keys.just_pressed(KeyCode::Return) || action_state.just_pressed(InputCommand::ACTION)
+}
+
+// Systems of the next state run in the same frame and must not see the press again
+fn consume_transition_keys(keys: &mut Input<KeyCode>) {
+    keys.reset(KeyCode::Escape);
+    keys.reset(KeyCode::Return);
+}
+
+// Enter or action starts the game, escape quits
+fn menu_system(
+    mut keys: ResMut<Input<KeyCode>>,
+    action_state: Res<ActionState<InputCommand>>,
+    mut app_state: ResMut<State<AppState>>,
+    mut exit_events: EventWriter<AppExit>,
+) {
+    if keys.just_pressed(KeyCode::Escape) {
+        exit_events.send(AppExit);
+    } else if confirm_pressed(&keys, &action_state) {
+        match app_state.set(AppState::PLAYING) {
+            Ok(()) => consume_transition_keys(&mut keys),
+            Err(e) => warn!("Failed to start the game: {:?}", e),
+        }
+    }
+}
+
+fn pause_input_system(mut keys: ResMut<Input<KeyCode>>, mut app_state: ResMut<State<AppState>>) {
+    if keys.just_pressed(KeyCode::Escape) {
+        match app_state.push(AppState::PAUSED) {
+            Ok(()) => consume_transition_keys(&mut keys),
+            Err(e) => warn!("Failed to pause: {:?}", e),
+        }
+    }
+}
+
+// Escape resumes, Q goes back to the menu, the number keys rebind the first binding of a
+// command to the next pressed input, with shift the second one
+fn paused_system(
+    mut keys: ResMut<Input<KeyCode>>,
+    mut input_map: ResMut<InputMap>,
+    pending_rebind: Res<PendingRebind>,
+    mut rebind_events: EventWriter<RebindEvent>,
+    mut app_state: ResMut<State<AppState>>,
+) {
+    // The next input belongs to the rebind
+    if pending_rebind.0.is_some() {
+        return;
+    }
+    if let Some((_, command)) = REBIND_KEYS.iter().find(|(key, _)| keys.just_pressed(*key)) {
+        // Control goes back to the default bindings of the command
+        if keys.any_pressed([KeyCode::LControl, KeyCode::RControl]) {
+            info!("Reset the bindings of {:?}", command);
+            input_map.reset(*command);
+            input_map.save_overrides();
+            return;
+        }
+        // Shift picks the second binding of the command
+        let index = if keys.any_pressed([KeyCode::LShift, KeyCode::RShift]) {
+            1
+        } else {
+            0
+        };
+        info!(
+            "Press the new binding {} for {:?}, escape cancels",
+            index, command
+        );
+        rebind_events.send(RebindEvent {
+            command: *command,
+            index,
+        });
+        return;
+    }
+
+    let result = if keys.just_pressed(KeyCode::Escape) {
+        app_state.pop()
+    } else if keys.just_pressed(KeyCode::Q) {
+        app_state.replace(AppState::MENU)
+    } else {
+        return;
+    };
+    match result {
+        Ok(()) => consume_transition_keys(&mut keys),
+        Err(e) => warn!("Failed to leave pause: {:?}", e),
+    }
+}
+
+// Enter or action restarts the level, escape goes back to the menu
+fn game_over_system(
+    mut keys: ResMut<Input<KeyCode>>,
+    action_state: Res<ActionState<InputCommand>>,
+    mut app_state: ResMut<State<AppState>>,
+) {
+    let result = if keys.just_pressed(KeyCode::Escape) {
+        app_state.set(AppState::MENU)
+    } else if confirm_pressed(&keys, &action_state) {
+        app_state.set(AppState::PLAYING)
+    } else {
+        return;
+    };
+    match result {
+        Ok(()) => consume_transition_keys(&mut keys),
+        Err(e) => warn!("Failed to leave game over: {:?}", e),
+    }
+}
+
+fn pause_animations_system(mut query: Query<&mut AnimationPlayer>) {
+    for mut player in query.iter_mut() {
+        player.pause();
+    }
+}
+
+fn resume_animations_system(mut query: Query<&mut AnimationPlayer>) {
+    for mut player in query.iter_mut() {
+        player.resume();
+    }
+}
+
+fn simulation_pause_system(app_state: Res<State<AppState>>, mut clock: ResMut<SimulationClock>) {
+    let paused = *app_state.current() != AppState::PLAYING;
+    if clock.paused != paused {
+        clock.paused = paused;
+    }
+}
+
+// Keeps a camera around for the UI while there's no level
+fn menu_camera_system(
+    mut commands: Commands,
+    app_state: Res<State<AppState>>,
+    query: Query<Entity, With<MenuCamera>>,
+) {
+    let needed = !matches!(app_state.current(), AppState::PLAYING | AppState::PAUSED);
+    match (needed, query.get_single()) {
+        (true, Err(_)) => {
+            commands
+                .spawn_bundle(Camera2dBundle::default())
+                .insert(MenuCamera);
+        }
+        (false, Ok(camera)) => {
+            commands.entity(camera).despawn_recursive();
+        }
+        _ => {}
+    }
+}
+
+// Runs in the simulation while the dead player still exists
+fn player_death_system(
+    mut death_events: EventReader<DeathEvent>,
+    players: Query<(), With<Player>>,
+    mut app_state: ResMut<State<AppState>>,
+) {
+    for e in death_events.iter() {
+        if players.get(e.entity).is_ok() {
+            if let Err(e) = app_state.set(AppState::GAMEOVER) {
+                warn!("Failed to end the game: {:?}", e);
+            }
+        }
+    }
+}
+
+pub struct AppStatePlugin;
+
+impl Plugin for AppStatePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_state(AppState::BOOT)
+            .add_system_set(SystemSet::on_update(AppState::BOOT).with_system(boot_system))
+            .add_system_set(
+                SystemSet::on_enter(AppState::LOADING)
+                    .with_system(asset_manifest_load_system)
+                    .with_system(loading_ui_spawn_system),
+            )
+            .add_system_set(
+                SystemSet::on_update(AppState::LOADING)
+                    .with_system(asset_manifest_progress_system)
+                    .with_system(loading_progress_system.after(asset_manifest_progress_system)),
+            )
+            .add_system_set(
+                SystemSet::on_exit(AppState::LOADING).with_system(state_ui_despawn_system),
+            )
+            .add_system_set(SystemSet::on_enter(AppState::MENU).with_system(menu_ui_spawn_system))
+            .add_system_set(SystemSet::on_update(AppState::MENU).with_system(menu_system))
+            .add_system_set(SystemSet::on_exit(AppState::MENU).with_system(state_ui_despawn_system))
+            .add_system_set(SystemSet::on_update(AppState::PLAYING).with_system(pause_input_system))
+            .add_system_set(
+                SystemSet::on_pause(AppState::PLAYING).with_system(pause_animations_system),
+            )
+            .add_system_set(
+                SystemSet::on_resume(AppState::PLAYING).with_system(resume_animations_system),
+            )
+            .add_system_set(
+                SystemSet::on_enter(AppState::PAUSED).with_system(pause_ui_spawn_system),
+            )
+            .add_system_set(
+                SystemSet::on_update(AppState::PAUSED)
+                    .with_system(paused_system.before(rebind_system)),
+            )
+            .add_system_set(
+                SystemSet::on_exit(AppState::PAUSED).with_system(state_ui_despawn_system),
+            )
+            .add_system_set(
+                SystemSet::on_enter(AppState::GAMEOVER).with_system(game_over_ui_spawn_system),
+            )
+            .add_system_set(SystemSet::on_update(AppState::GAMEOVER).with_system(game_over_system))
+            .add_system_set(
+                SystemSet::on_exit(AppState::GAMEOVER).with_system(state_ui_despawn_system),
+            )
+            .add_system(simulation_pause_system)
+            .add_system(menu_camera_system)
+            .add_system_to_stage(
+                SimulationStage,
+                player_death_system
+                    .after(SimulationSystem::COMBAT)
+                    .before(SimulationSystem::FINALIZE),
+            );
+    }
+
+    fn name(&self) -> &str {
+        "AppStatePlugin"
+    }
+}
@@ -0,0 +1,88 @@
+use bevy::{asset::LoadState, prelude::*};
+
+use crate::level::{Level, LEVEL_PATH};
+
+// Assets a level needs, kept loaded so spawning the level finds them ready
+pub struct AssetManifest {
+    pub assets: Vec<(String, HandleUntyped)>,
+}
+
+impl AssetManifest {
+    pub fn load(asset_server: &AssetServer, paths: Vec<String>) -> AssetManifest {
+        let assets = paths
+            .into_iter()
+            .map(|path| {
+                let handle = asset_server.load_untyped(path.as_str());
+                (path, handle)
+            })
+            .collect();
+        return AssetManifest { assets };
+    }
+}
+
+#[derive(Default)]
+pub struct AssetLoadProgress {
+    pub loaded: usize,
+    // Paths of the assets that failed, reported once each
+    pub failed: Vec<String>,
+    pub total: usize,
+}
+
+impl AssetLoadProgress {
+    // In 0..1, failed assets count as finished
+    pub fn fraction(&self) -> f32 {
+        if self.total == 0 {
+            return 1.0;
+        }
+        (self.loaded + self.failed.len()) as f32 / self.total as f32
+    }
+
+    pub fn finished(&self) -> bool {
+        self.loaded + self.failed.len() >= self.total
+    }
+}
+
+// Starts loading everything the level references
+pub fn asset_manifest_load_system(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let paths = match Level::load(LEVEL_PATH) {
+        Ok(level) => level.required_assets(),
+        Err(e) => {
+            error!("Failed to load level from {}: {}", LEVEL_PATH, e);
+            Vec::new()
+        }
+    };
+    let manifest = AssetManifest::load(&asset_server, paths);
+    commands.insert_resource(AssetLoadProgress {
+        total: manifest.assets.len(),
+        ..default()
+    });
+    commands.insert_resource(manifest);
+}
+
+// The resources appear once the commands of the load system are applied
+pub fn asset_manifest_progress_system(
+    asset_server: Res<AssetServer>,
+    manifest: Option<Res<AssetManifest>>,
+    progress: Option<ResMut<AssetLoadProgress>>,
+) {
+    let (manifest, mut progress) = match (manifest, progress) {
+        (Some(manifest), Some(progress)) => (manifest, progress),
+        _ => return,
+    };
+    let mut loaded = 0;
+    for (path, handle) in manifest.assets.iter() {
+        match asset_server.get_load_state(handle.id) {
+            LoadState::Loaded => loaded += 1,
+            LoadState::Failed => {
+                if !progress.failed.contains(path) {
+                    error!("Failed to load level asset {}", path);
+                    progress.failed.push(path.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+    if progress.loaded != loaded {
+        progress.loaded = loaded;
+    }
+}
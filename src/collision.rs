@@ -0,0 +1,583 @@
+use std::sync::Arc;
+
+use bevy::{
+    prelude::*,
+    render::mesh::{Indices, VertexAttributeValues},
+};
+
+use crate::simulation::{SimulationStage, SimulationSystem, SIMULATION_TIMESTEP};
+
+// Resolution passes per tick, more passes settle corners between several colliders
+const RESOLVE_ITERATIONS: usize = 4;
+// Characters within this distance above the ground stick to it, e.g. walking down a slope
+const GROUND_SNAP_DISTANCE: f32 = 0.3;
+// Speed characters drop with when there is no ground within snap distance
+const FALL_SPEED: f32 = 9.0;
+
+#[derive(Clone, Debug)]
+pub enum ColliderShape {
+    // Oriented box, characters assume boxes are only rotated around the y axis
+    Box { half_extents: Vec3 },
+    Sphere { radius: f32 },
+    // Triangles in the local space of the entity
+    Mesh { triangles: Arc<Vec<[Vec3; 3]>> },
+}
+
+impl ColliderShape {
+    pub fn from_mesh(mesh: &Mesh) -> Option<ColliderShape> {
+        let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION)? {
+            VertexAttributeValues::Float32x3(positions) => positions,
+            _ => return None,
+        };
+        let indices: Vec<usize> = match mesh.indices() {
+            Some(Indices::U16(indices)) => indices.iter().map(|i| *i as usize).collect(),
+            Some(Indices::U32(indices)) => indices.iter().map(|i| *i as usize).collect(),
+            None => (0..positions.len()).collect(),
+        };
+        let triangles = indices
+            .chunks_exact(3)
+            .map(|t| {
+                [
+                    Vec3::from(positions[t[0]]),
+                    Vec3::from(positions[t[1]]),
+                    Vec3::from(positions[t[2]]),
+                ]
+            })
+            .collect();
+        Some(ColliderShape::Mesh {
+            triangles: Arc::new(triangles),
+        })
+    }
+
+    // Half size of the local bounding box around the origin
+    pub fn half_extents(&self) -> Vec3 {
+        match self {
+            ColliderShape::Box { half_extents } => *half_extents,
+            ColliderShape::Sphere { radius } => Vec3::splat(*radius),
+            ColliderShape::Mesh { triangles } => triangles
+                .iter()
+                .flatten()
+                .fold(Vec3::ZERO, |extents, v| extents.max(v.abs())),
+        }
+    }
+}
+
+// Level geometry that never moves by itself
+#[derive(Component, Clone, Debug)]
+pub struct StaticCollider(pub ColliderShape);
+
+// Upright cylinder from the entity origin up to the height, the entity origin is at the feet
+#[derive(Component, Clone, Copy, Debug)]
+pub struct CharacterCollider {
+    pub radius: f32,
+    pub height: f32,
+    // Geometry up to this height above the feet is stepped onto instead of blocking
+    pub step_height: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RayHit {
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub distance: f32,
+}
+
+// Characters are kept inside this area on the xz plane
+pub struct CollisionBounds {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+impl Default for CollisionBounds {
+    fn default() -> Self {
+        return Self {
+            min: Vec2::new(-10.0, -10.0),
+            max: Vec2::new(10.0, 10.0),
+        };
+    }
+}
+
+// Horizontal push that moves the character out of the collider, if they overlap
+pub fn penetration(
+    position: Vec3,
+    character: &CharacterCollider,
+    transform: &Transform,
+    shape: &ColliderShape,
+) -> Option<Vec3> {
+    let push = match shape {
+        ColliderShape::Box { half_extents } => box_penetration(
+            position,
+            character,
+            transform,
+            *half_extents * transform.scale,
+        ),
+        ColliderShape::Sphere { radius } => sphere_penetration(
+            position,
+            character,
+            transform.translation,
+            radius * transform.scale.max_element(),
+        ),
+        ColliderShape::Mesh { triangles } => {
+            mesh_penetration(position, character, transform, triangles)
+        }
+    };
+    push.filter(|p| p.length_squared() > 0.0)
+}
+
+fn box_penetration(
+    position: Vec3,
+    character: &CharacterCollider,
+    transform: &Transform,
+    half_extents: Vec3,
+) -> Option<Vec3> {
+    let bottom = transform.translation.y - half_extents.y;
+    let top = transform.translation.y + half_extents.y;
+    if position.y + character.step_height >= top || position.y + character.height <= bottom {
+        return None;
+    }
+
+    let mut offset = position - transform.translation;
+    offset.y = 0.0;
+    let local = transform.rotation.inverse() * offset;
+    let local = Vec2::new(local.x, local.z);
+    let extents = Vec2::new(half_extents.x, half_extents.z);
+
+    let closest = local.clamp(-extents, extents);
+    let local_push = if closest == local {
+        // Center is inside the box, leave through the closest side
+        let depth = extents - local.abs();
+        if depth.x < depth.y {
+            Vec2::new((depth.x + character.radius) * local.x.signum(), 0.0)
+        } else {
+            Vec2::new(0.0, (depth.y + character.radius) * local.y.signum())
+        }
+    } else {
+        let delta = local - closest;
+        let distance = delta.length();
+        if distance >= character.radius {
+            return None;
+        }
+        delta / distance * (character.radius - distance)
+    };
+    Some(transform.rotation * Vec3::new(local_push.x, 0.0, local_push.y))
+}
+
+fn sphere_penetration(
+    position: Vec3,
+    character: &CharacterCollider,
+    center: Vec3,
+    radius: f32,
+) -> Option<Vec3> {
+    if position.y + character.step_height >= center.y + radius {
+        return None;
+    }
+    // Radius of the sphere slice at the closest height of the cylinder
+    let closest_y = center.y.clamp(position.y, position.y + character.height);
+    let dy = center.y - closest_y;
+    if dy.abs() >= radius {
+        return None;
+    }
+    let slice_radius = (radius * radius - dy * dy).sqrt();
+    let mut delta = position - center;
+    delta.y = 0.0;
+    let distance = delta.length();
+    let min_distance = slice_radius + character.radius;
+    if distance >= min_distance {
+        return None;
+    }
+    let normal = if distance > 0.0 {
+        delta / distance
+    } else {
+        Vec3::X
+    };
+    Some(normal * (min_distance - distance))
+}
+
+fn mesh_penetration(
+    position: Vec3,
+    character: &CharacterCollider,
+    transform: &Transform,
+    triangles: &[[Vec3; 3]],
+) -> Option<Vec3> {
+    let matrix = transform.compute_matrix();
+    let mut push = Vec3::ZERO;
+    // The cylinder above the step height is approximated by spheres stacked along its axis
+    let step = character.step_height + character.radius;
+    let samples = ((character.height - step) / character.radius)
+        .ceil()
+        .max(1.0) as usize;
+    for triangle in triangles {
+        let triangle = triangle.map(|v| matrix.transform_point3(v));
+        for i in 0..=samples {
+            let height = step + (i as f32 / samples as f32) * (character.height - step).max(0.0);
+            let center = position + push + Vec3::Y * height;
+            let closest = closest_point_on_triangle(center, &triangle);
+            let mut delta = center - closest;
+            let distance = delta.length();
+            delta.y = 0.0;
+            // Ground below and ceilings above don't push sideways
+            let horizontal = delta.length();
+            if distance < character.radius && horizontal > 0.001 {
+                push += delta / horizontal * (character.radius - distance);
+            }
+        }
+    }
+    Some(push)
+}
+
+pub fn closest_point_on_triangle(p: Vec3, triangle: &[Vec3; 3]) -> Vec3 {
+    let [a, b, c] = *triangle;
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+    let bp = p - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+    let cp = p - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+    let denom = 1.0 / (va + vb + vc);
+    a + ab * (vb * denom) + ac * (vc * denom)
+}
+
+// Closest hit of the ray with the collider, direction has to be normalized
+pub fn raycast(
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    transform: &Transform,
+    shape: &ColliderShape,
+) -> Option<RayHit> {
+    let hit = match shape {
+        ColliderShape::Box { half_extents } => {
+            raycast_box(origin, direction, transform, *half_extents)
+        }
+        ColliderShape::Sphere { radius } => raycast_sphere(
+            origin,
+            direction,
+            transform.translation,
+            radius * transform.scale.max_element(),
+        ),
+        ColliderShape::Mesh { triangles } => {
+            let matrix = transform.compute_matrix();
+            triangles
+                .iter()
+                .filter_map(|triangle| {
+                    raycast_triangle(
+                        origin,
+                        direction,
+                        &triangle.map(|v| matrix.transform_point3(v)),
+                    )
+                })
+                .min_by(|a, b| a.distance.total_cmp(&b.distance))
+        }
+    };
+    hit.filter(|hit| hit.distance <= max_distance)
+}
+
+// Closest hit over all colliders
+pub fn raycast_all<'a>(
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    colliders: impl Iterator<Item = (&'a Transform, &'a ColliderShape)>,
+) -> Option<RayHit> {
+    colliders
+        .filter_map(|(transform, shape)| raycast(origin, direction, max_distance, transform, shape))
+        .min_by(|a, b| a.distance.total_cmp(&b.distance))
+}
+
+fn raycast_box(
+    origin: Vec3,
+    direction: Vec3,
+    transform: &Transform,
+    half_extents: Vec3,
+) -> Option<RayHit> {
+    // Slab test in the local space of the box, the ray parameter stays in world units
+    let inverse = transform.compute_matrix().inverse();
+    let local_origin = inverse.transform_point3(origin);
+    let local_direction = inverse.transform_vector3(direction);
+
+    let mut t_min = 0.0_f32;
+    let mut t_max = f32::INFINITY;
+    let mut normal = Vec3::ZERO;
+    for axis in 0..3 {
+        if local_direction[axis].abs() < f32::EPSILON {
+            if local_origin[axis].abs() > half_extents[axis] {
+                return None;
+            }
+            continue;
+        }
+        let inv = 1.0 / local_direction[axis];
+        let mut t0 = (-half_extents[axis] - local_origin[axis]) * inv;
+        let mut t1 = (half_extents[axis] - local_origin[axis]) * inv;
+        if t0 > t1 {
+            std::mem::swap(&mut t0, &mut t1);
+        }
+        if t0 > t_min {
+            t_min = t0;
+            normal = Vec3::ZERO;
+            normal[axis] = -local_direction[axis].signum();
+        }
+        t_max = t_max.min(t1);
+        if t_min > t_max {
+            return None;
+        }
+    }
+    Some(RayHit {
+        point: origin + direction * t_min,
+        normal: (transform.rotation * normal).normalize_or_zero(),
+        distance: t_min,
+    })
+}
+
+fn raycast_sphere(origin: Vec3, direction: Vec3, center: Vec3, radius: f32) -> Option<RayHit> {
+    let offset = origin - center;
+    let b = offset.dot(direction);
+    let c = offset.length_squared() - radius * radius;
+    let discriminant = b * b - c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let distance = (-b - discriminant.sqrt()).max(0.0);
+    let point = origin + direction * distance;
+    Some(RayHit {
+        point,
+        normal: (point - center).normalize_or_zero(),
+        distance,
+    })
+}
+
+// Möller-Trumbore, hits from both sides
+fn raycast_triangle(origin: Vec3, direction: Vec3, triangle: &[Vec3; 3]) -> Option<RayHit> {
+    let [a, b, c] = *triangle;
+    let ab = b - a;
+    let ac = c - a;
+    let p = direction.cross(ac);
+    let determinant = ab.dot(p);
+    if determinant.abs() < f32::EPSILON {
+        return None;
+    }
+    let inv = 1.0 / determinant;
+    let ao = origin - a;
+    let u = ao.dot(p) * inv;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = ao.cross(ab);
+    let v = direction.dot(q) * inv;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let distance = ac.dot(q) * inv;
+    if distance < 0.0 {
+        return None;
+    }
+    let mut normal = ab.cross(ac).normalize_or_zero();
+    if normal.dot(direction) > 0.0 {
+        normal = -normal;
+    }
+    Some(RayHit {
+        point: origin + direction * distance,
+        normal,
+        distance,
+    })
+}
+
+// Moves the character out of all colliders, only the part of the motion going into
+// a collider is removed so characters slide along walls
+pub fn resolve_character(
+    position: Vec3,
+    character: &CharacterCollider,
+    statics: &[(Transform, &ColliderShape)],
+    bounds: &CollisionBounds,
+) -> Vec3 {
+    let mut position = position;
+    for _ in 0..RESOLVE_ITERATIONS {
+        let mut resolved = true;
+        for (transform, shape) in statics.iter() {
+            if let Some(push) = penetration(position, character, transform, shape) {
+                position += push;
+                resolved = false;
+            }
+        }
+        if resolved {
+            break;
+        }
+    }
+    position.x = position.x.clamp(
+        bounds.min.x + character.radius,
+        bounds.max.x - character.radius,
+    );
+    position.z = position.z.clamp(
+        bounds.min.y + character.radius,
+        bounds.max.y - character.radius,
+    );
+    position
+}
+
+pub fn character_collision_system(
+    bounds: Res<CollisionBounds>,
+    statics: Query<(&Transform, &StaticCollider), Without<CharacterCollider>>,
+    mut characters: Query<(&mut Transform, &CharacterCollider)>,
+) {
+    let statics: Vec<(Transform, &ColliderShape)> = statics
+        .iter()
+        .map(|(transform, collider)| (*transform, &collider.0))
+        .collect();
+    for (mut transform, character) in characters.iter_mut() {
+        let resolved = resolve_character(transform.translation, character, &statics, &bounds);
+        if resolved != transform.translation {
+            transform.translation = resolved;
+        }
+    }
+}
+
+// Keeps characters on top of the ground below them, stepping up and down ledges up to
+// their step height and falling when there is nothing close below
+pub fn ground_snap_system(
+    statics: Query<(&Transform, &StaticCollider), Without<CharacterCollider>>,
+    mut characters: Query<(&mut Transform, &CharacterCollider)>,
+) {
+    for (mut transform, character) in characters.iter_mut() {
+        let origin = transform.translation + Vec3::Y * character.step_height;
+        let hit = raycast_all(
+            origin,
+            -Vec3::Y,
+            f32::INFINITY,
+            statics.iter().map(|(t, collider)| (t, &collider.0)),
+        );
+        if let Some(hit) = hit {
+            let drop = transform.translation.y - hit.point.y;
+            let fall_step = FALL_SPEED * SIMULATION_TIMESTEP;
+            let y = if drop <= GROUND_SNAP_DISTANCE || drop <= fall_step {
+                hit.point.y
+            } else {
+                transform.translation.y - fall_step
+            };
+            if y != transform.translation.y {
+                transform.translation.y = y;
+            }
+        }
+    }
+}
+
+pub struct CollisionPlugin;
+
+impl Plugin for CollisionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CollisionBounds>()
+            .add_system_to_stage(
+                SimulationStage,
+                character_collision_system
+                    .label(SimulationSystem::COLLISION)
+                    .after(SimulationSystem::MOVEMENT)
+                    .before(SimulationSystem::FINALIZE),
+            )
+            .add_system_to_stage(
+                SimulationStage,
+                ground_snap_system
+                    .label(SimulationSystem::GROUND)
+                    .after(SimulationSystem::COLLISION)
+                    .before(SimulationSystem::FINALIZE),
+            );
+    }
+
+    fn name(&self) -> &str {
+        "CollisionPlugin"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHARACTER: CharacterCollider = CharacterCollider {
+        radius: 0.5,
+        height: 1.8,
+        step_height: 0.3,
+    };
+
+    fn resolve(position: Vec3, transform: Transform, shape: ColliderShape) -> Vec3 {
+        resolve_character(
+            position,
+            &CHARACTER,
+            &[(transform, &shape)],
+            &CollisionBounds::default(),
+        )
+    }
+
+    #[test]
+    fn box_pushes_out_along_its_side_and_keeps_the_sliding_motion() {
+        let shape = ColliderShape::Box {
+            half_extents: Vec3::ONE,
+        };
+        let resolved = resolve(Vec3::new(1.3, 0.0, 0.4), Transform::identity(), shape);
+        assert!((resolved - Vec3::new(1.5, 0.0, 0.4)).length() < 1e-5);
+    }
+
+    #[test]
+    fn box_below_the_step_height_doesnt_block() {
+        let shape = ColliderShape::Box {
+            half_extents: Vec3::new(1.0, 0.1, 1.0),
+        };
+        let position = Vec3::new(1.3, 0.0, 0.4);
+        let resolved = resolve(position, Transform::from_xyz(0.0, 0.1, 0.0), shape);
+        assert_eq!(resolved, position);
+    }
+
+    #[test]
+    fn sphere_pushes_out_radially() {
+        let shape = ColliderShape::Sphere { radius: 1.0 };
+        let resolved = resolve(Vec3::new(0.9, 0.0, 0.9), Transform::identity(), shape);
+        // Sphere radius plus character radius, in the direction the character came from
+        assert!((resolved.length() - 1.5).abs() < 1e-5);
+        assert!((resolved.x - resolved.z).abs() < 1e-5);
+        assert_eq!(resolved.y, 0.0);
+    }
+
+    #[test]
+    fn mesh_wall_pushes_out_and_keeps_the_sliding_motion() {
+        // Quad in the x = 1 plane facing -x
+        let triangles = vec![
+            [
+                Vec3::new(1.0, 0.0, -2.0),
+                Vec3::new(1.0, 0.0, 2.0),
+                Vec3::new(1.0, 2.0, 2.0),
+            ],
+            [
+                Vec3::new(1.0, 0.0, -2.0),
+                Vec3::new(1.0, 2.0, 2.0),
+                Vec3::new(1.0, 2.0, -2.0),
+            ],
+        ];
+        let shape = ColliderShape::Mesh {
+            triangles: Arc::new(triangles),
+        };
+        let resolved = resolve(Vec3::new(0.7, 0.0, 0.3), Transform::identity(), shape);
+        assert!((resolved - Vec3::new(0.5, 0.0, 0.3)).length() < 1e-4);
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    action_state::{action_state_system, ActionState},
+    ai::AiAttackEvent,
+    animation::{animation_graph_system, AnimationEvent},
+    app_state::in_game,
+    input::InputCommand,
+    simulation::{SimulationStage, SimulationSystem, SIMULATION_TIMESTEP},
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum DamageKind {
+    PHYSICAL,
+    POISON,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Damage {
+    pub amount: f32,
+    pub kind: DamageKind,
+}
+
+#[derive(Component, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Health {
+    pub fn new(max: f32) -> Health {
+        return Health { current: max, max };
+    }
+}
+
+// Damage multiplier per kind, kinds that are missing take full damage
+#[derive(Component, Default)]
+pub struct Resistances(pub HashMap<DamageKind, f32>);
+
+// Ignores damage for the duration after every hit
+#[derive(Component)]
+pub struct Invulnerability {
+    pub duration: f32,
+    remaining: f32,
+}
+
+impl Invulnerability {
+    pub fn new(duration: f32) -> Invulnerability {
+        return Invulnerability {
+            duration,
+            remaining: 0.0,
+        };
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.remaining > 0.0
+    }
+}
+
+// Sphere relative to the entity that damages hurtboxes while active, every entity is
+// only hit once per activation
+#[derive(Component)]
+pub struct Hitbox {
+    pub offset: Vec3,
+    pub radius: f32,
+    pub damage: Damage,
+    // Scales the damage of the current activation, e.g. for charged attacks
+    pub multiplier: f32,
+    active: bool,
+    hit: Vec<Entity>,
+}
+
+impl Hitbox {
+    pub fn new(offset: Vec3, radius: f32, damage: Damage) -> Hitbox {
+        return Hitbox {
+            offset,
+            radius,
+            damage,
+            multiplier: 1.0,
+            active: false,
+            hit: Vec::new(),
+        };
+    }
+
+    pub fn set_active(&mut self, active: bool) {
+        if active && !self.active {
+            self.hit.clear();
+        }
+        self.active = active;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+}
+
+// Upright cylinder from the entity origin that hitboxes can damage
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Hurtbox {
+    pub radius: f32,
+    pub height: f32,
+}
+
+// Damage dealt by AI attacks of the entity
+#[derive(Component, Clone, Copy, Debug)]
+pub struct AttackDamage(pub Damage);
+
+// Animation notifies that open and close the hitbox of a melee attack
+pub const HIT_START_NOTIFY: &str = "hit_start";
+pub const HIT_END_NOTIFY: &str = "hit_end";
+
+// Melee swing of the player, the hitbox is active between the hit notifies of the attack
+// animation, or between hit_start and hit_end seconds into the attack without them.
+// Releasing the action after holding it for the charge time swings a charged attack
+#[derive(Component)]
+pub struct MeleeAttack {
+    pub duration: f32,
+    pub hit_start: f32,
+    pub hit_end: f32,
+    pub charge_time: f32,
+    pub charged_multiplier: f32,
+    pub requested: bool,
+    pub charged: bool,
+    elapsed: Option<f32>,
+    // Set by the hit notifies during the current attack
+    animation_hit: Option<bool>,
+}
+
+impl MeleeAttack {
+    pub fn new(duration: f32, hit_start: f32, hit_end: f32) -> MeleeAttack {
+        return MeleeAttack {
+            duration,
+            hit_start,
+            hit_end,
+            charge_time: 0.8,
+            charged_multiplier: 2.0,
+            requested: false,
+            charged: false,
+            elapsed: None,
+            animation_hit: None,
+        };
+    }
+
+    pub fn is_attacking(&self) -> bool {
+        self.elapsed.is_some()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DamageEvent {
+    pub target: Entity,
+    pub source: Option<Entity>,
+    pub damage: Damage,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DeathEvent {
+    pub entity: Entity,
+    pub source: Option<Entity>,
+}
+
+// Input is sampled per frame, the attack itself runs on the simulation ticks
+fn attack_input_system(
+    action_state: Res<ActionState<InputCommand>>,
+    mut query: Query<&mut MeleeAttack>,
+) {
+    let released_after = action_state
+        .just_released(InputCommand::ACTION)
+        .then(|| action_state.held_duration(InputCommand::ACTION));
+    for mut attack in query.iter_mut() {
+        if action_state.just_pressed(InputCommand::ACTION) {
+            attack.requested = true;
+        } else if released_after.map_or(false, |held| held >= attack.charge_time) {
+            attack.requested = true;
+            attack.charged = true;
+        }
+    }
+}
+
+fn melee_attack_system(mut query: Query<(&mut MeleeAttack, &mut Hitbox)>) {
+    for (mut attack, mut hitbox) in query.iter_mut() {
+        if attack.requested && attack.elapsed.is_none() {
+            attack.elapsed = Some(0.0);
+            hitbox.multiplier = if attack.charged {
+                attack.charged_multiplier
+            } else {
+                1.0
+            };
+        }
+        attack.requested = false;
+        attack.charged = false;
+
+        if let Some(elapsed) = attack.elapsed {
+            let elapsed = elapsed + SIMULATION_TIMESTEP;
+            if elapsed >= attack.duration {
+                attack.elapsed = None;
+                attack.animation_hit = None;
+                hitbox.set_active(false);
+            } else {
+                let timed = elapsed >= attack.hit_start && elapsed < attack.hit_end;
+                attack.elapsed = Some(elapsed);
+                hitbox.set_active(attack.animation_hit.unwrap_or(timed));
+            }
+        }
+    }
+}
+
+// Notifies arrive per frame, the hitbox follows them on the next simulation tick
+fn melee_animation_notify_system(
+    mut animation_events: EventReader<AnimationEvent>,
+    mut query: Query<&mut MeleeAttack>,
+) {
+    for e in animation_events.iter() {
+        let mut attack = match query.get_mut(e.entity) {
+            Ok(attack) => attack,
+            Err(_) => continue,
+        };
+        if !attack.is_attacking() {
+            continue;
+        }
+        match e.name.as_str() {
+            HIT_START_NOTIFY => attack.animation_hit = Some(true),
+            HIT_END_NOTIFY => attack.animation_hit = Some(false),
+            _ => {}
+        }
+    }
+}
+
+fn hitbox_system(
+    mut hitboxes: Query<(Entity, &Transform, &mut Hitbox)>,
+    hurtboxes: Query<(Entity, &Transform, &Hurtbox)>,
+    mut damage_events: EventWriter<DamageEvent>,
+) {
+    for (entity, transform, mut hitbox) in hitboxes.iter_mut() {
+        if !hitbox.active {
+            continue;
+        }
+        let center = transform.mul_vec3(hitbox.offset);
+        for (target, target_transform, hurtbox) in hurtboxes.iter() {
+            if target == entity || hitbox.hit.contains(&target) {
+                continue;
+            }
+            // Closest point on the hurtbox axis, then a sphere against circle test
+            let base = target_transform.translation;
+            let closest_y = center.y.clamp(base.y, base.y + hurtbox.height);
+            let closest = Vec3::new(base.x, closest_y, base.z);
+            let mut offset = center - closest;
+            let vertical = offset.y;
+            offset.y = 0.0;
+            if vertical.abs() > hitbox.radius {
+                continue;
+            }
+            let slice_radius = (hitbox.radius * hitbox.radius - vertical * vertical).sqrt();
+            if offset.length() <= slice_radius + hurtbox.radius {
+                hitbox.hit.push(target);
+                damage_events.send(DamageEvent {
+                    target,
+                    source: Some(entity),
+                    damage: Damage {
+                        amount: hitbox.damage.amount * hitbox.multiplier,
+                        kind: hitbox.damage.kind,
+                    },
+                });
+            }
+        }
+    }
+}
+
+fn ai_attack_system(
+    mut attack_events: EventReader<AiAttackEvent>,
+    attackers: Query<&AttackDamage>,
+    mut damage_events: EventWriter<DamageEvent>,
+) {
+    for e in attack_events.iter() {
+        if let Ok(attack_damage) = attackers.get(e.attacker) {
+            damage_events.send(DamageEvent {
+                target: e.target,
+                source: Some(e.attacker),
+                damage: attack_damage.0,
+            });
+        }
+    }
+}
+
+fn apply_damage_system(
+    mut damage_events: EventReader<DamageEvent>,
+    mut query: Query<(
+        &mut Health,
+        Option<&Resistances>,
+        Option<&mut Invulnerability>,
+    )>,
+    mut death_events: EventWriter<DeathEvent>,
+) {
+    for e in damage_events.iter() {
+        let (mut health, resistances, invulnerability) = match query.get_mut(e.target) {
+            Ok(target) => target,
+            Err(_) => continue,
+        };
+        if health.current <= 0.0 {
+            continue;
+        }
+        if let Some(mut invulnerability) = invulnerability {
+            if invulnerability.is_active() {
+                continue;
+            }
+            invulnerability.remaining = invulnerability.duration;
+        }
+
+        let multiplier = resistances
+            .and_then(|r| r.0.get(&e.damage.kind))
+            .copied()
+            .unwrap_or(1.0);
+        health.current = (health.current - e.damage.amount * multiplier).max(0.0);
+
+        if health.current <= 0.0 {
+            death_events.send(DeathEvent {
+                entity: e.target,
+                source: e.source,
+            });
+        }
+    }
+}
+
+fn invulnerability_system(mut query: Query<&mut Invulnerability>) {
+    for mut invulnerability in query.iter_mut() {
+        if invulnerability.remaining > 0.0 {
+            invulnerability.remaining -= SIMULATION_TIMESTEP;
+        }
+    }
+}
+
+fn death_system(mut commands: Commands, mut death_events: EventReader<DeathEvent>) {
+    for e in death_events.iter() {
+        info!("{:?} died", e.entity);
+        commands.entity(e.entity).despawn_recursive();
+    }
+}
+
+pub struct CombatPlugin;
+
+impl Plugin for CombatPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<DamageEvent>()
+            .add_event::<DeathEvent>()
+            .add_system(
+                attack_input_system
+                    .after(action_state_system)
+                    .with_run_criteria(in_game),
+            )
+            .add_system(melee_animation_notify_system.after(animation_graph_system))
+            .add_system_set_to_stage(
+                SimulationStage,
+                SystemSet::new()
+                    .label(SimulationSystem::COMBAT)
+                    .after(SimulationSystem::GROUND)
+                    .before(SimulationSystem::FINALIZE)
+                    .with_system(invulnerability_system)
+                    .with_system(melee_attack_system)
+                    .with_system(hitbox_system.after(melee_attack_system))
+                    .with_system(ai_attack_system)
+                    .with_system(
+                        apply_damage_system
+                            .after(hitbox_system)
+                            .after(ai_attack_system)
+                            .after(invulnerability_system),
+                    )
+                    .with_system(death_system.after(apply_damage_system)),
+            );
+    }
+
+    fn name(&self) -> &str {
+        "CombatPlugin"
+    }
+}
@@ -1,6 +1,18 @@
 use bevy::prelude::*;
 
-use crate::{movement::MovementSpeed, NameV2};
+use crate::{
+    ai::AiController,
+    animation::{AnimationParameters, Animations},
+    collision::CharacterCollider,
+    combat::{AttackDamage, Damage, DamageKind, Health, Hurtbox, Invulnerability},
+    movement::{CharacterController, MovementIntent, MovementSpeed, MovementTarget},
+    navigation::NavAgent,
+    player::{set_velocity_parameters, MOVING_THRESHOLD},
+    root_motion::RootMotion,
+    simulation::SimulatedTransform,
+    steering::SteeringAgent,
+    NameV2,
+};
 
 #[derive(Component)]
 pub struct Enemy;
@@ -10,6 +22,21 @@ pub struct EnemyBundle {
     pub _e: Enemy,
     pub name: NameV2,
     pub movement_speed: MovementSpeed,
+    pub movement_target: MovementTarget,
+    pub movement_intent: MovementIntent,
+    pub controller: CharacterController,
+    pub nav_agent: NavAgent,
+    pub ai: AiController,
+    pub collider: CharacterCollider,
+    pub simulated_transform: SimulatedTransform,
+    pub steering: SteeringAgent,
+    pub health: Health,
+    pub hurtbox: Hurtbox,
+    pub invulnerability: Invulnerability,
+    pub attack_damage: AttackDamage,
+    pub animations: Animations,
+    pub animation_parameters: AnimationParameters,
+    pub root_motion: RootMotion,
     #[bundle]
     pub scene_bundle: SceneBundle,
 }
@@ -20,7 +47,51 @@ impl Default for EnemyBundle {
             _e: Enemy,
             name: NameV2("unknown".to_string()),
             movement_speed: MovementSpeed(1.0),
+            movement_target: MovementTarget {
+                current_target: None,
+                ..default()
+            },
+            movement_intent: MovementIntent::default(),
+            controller: CharacterController {
+                acceleration: 10.0,
+                turn_rate: 8.0,
+                ..default()
+            },
+            nav_agent: NavAgent::default(),
+            ai: AiController::new("spider"),
+            collider: CharacterCollider {
+                radius: 0.4,
+                height: 0.5,
+                step_height: 0.2,
+            },
+            simulated_transform: SimulatedTransform::default(),
+            steering: SteeringAgent::default(),
+            health: Health::new(50.0),
+            hurtbox: Hurtbox {
+                radius: 0.4,
+                height: 0.5,
+            },
+            invulnerability: Invulnerability::new(0.2),
+            attack_damage: AttackDamage(Damage {
+                amount: 10.0,
+                kind: DamageKind::POISON,
+            }),
+            animations: Animations::default(),
+            animation_parameters: AnimationParameters::default(),
+            root_motion: RootMotion::default(),
             scene_bundle: SceneBundle::default(),
         };
     }
 }
+
+// Exposes the movement of the enemy to the animation graph
+pub fn enemy_animation_parameters_system(
+    mut query: Query<(&Transform, &SimulatedTransform, &mut AnimationParameters), With<Enemy>>,
+) {
+    for (transform, simulated, mut parameters) in query.iter_mut() {
+        let velocity = simulated.velocity();
+        let planar_speed = Vec3::new(velocity.x, 0.0, velocity.z).length();
+        parameters.set_bool("moving", planar_speed > MOVING_THRESHOLD);
+        set_velocity_parameters(&mut parameters, transform, velocity);
+    }
+}
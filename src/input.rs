@@ -1,11 +1,20 @@
+use bevy::ecs::schedule::ShouldRun;
 use bevy::input::mouse::{MouseButtonInput, MouseMotion, MouseWheel};
 use bevy::input::InputSystem;
 use bevy::prelude::*;
 use bevy::render::camera::RenderTarget;
+use serde::{Deserialize, Serialize};
 
+use crate::action_state::{action_state_system, ActionState};
+use crate::app_state::{in_game, AppState};
+use crate::collision::{raycast_all, StaticCollider};
 use crate::debug::TestDebugComponent;
+use crate::input_map::{
+    apply_dead_zone, rebind_system, InputDevices, InputMap, PendingRebind, RebindEvent,
+};
+use crate::replay::{live_input, Replay};
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum InputCommand {
     LEFT,
     RIGHT,
@@ -14,37 +23,70 @@ pub enum InputCommand {
     ACTION,
 }
 
+impl InputCommand {
+    pub const ALL: [InputCommand; 5] = [
+        InputCommand::LEFT,
+        InputCommand::RIGHT,
+        InputCommand::UP,
+        InputCommand::DOWN,
+        InputCommand::ACTION,
+    ];
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct InputEvent(pub InputCommand);
 
-pub fn input_system(input: Res<Input<KeyCode>>, mut event: EventWriter<InputEvent>) {
-    if input.pressed(KeyCode::Left) {
-        event.send(InputEvent(InputCommand::LEFT));
-    }
-    if input.pressed(KeyCode::Right) {
-        event.send(InputEvent(InputCommand::RIGHT));
-    }
-    if input.pressed(KeyCode::Up) {
-        event.send(InputEvent(InputCommand::UP));
-    }
-    if input.pressed(KeyCode::Down) {
-        event.send(InputEvent(InputCommand::DOWN));
-    }
-    if input.pressed(KeyCode::A) {
-        event.send(InputEvent(InputCommand::LEFT));
-    }
-    if input.pressed(KeyCode::D) {
-        event.send(InputEvent(InputCommand::RIGHT));
-    }
-    if input.pressed(KeyCode::W) {
-        event.send(InputEvent(InputCommand::UP));
+// Analog movement in screen directions (x right, y up), length is in 0..1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MoveEvent(pub Vec2);
+
+pub fn input_system(
+    input_map: Res<InputMap>,
+    keys: Res<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    gamepads: Res<Gamepads>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    gamepad_button_axes: Res<Axis<GamepadButton>>,
+    gamepad_axes: Res<Axis<GamepadAxis>>,
+    mut event: EventWriter<InputEvent>,
+    mut move_event: EventWriter<MoveEvent>,
+) {
+    let devices = InputDevices {
+        keys: &keys,
+        mouse_buttons: &mouse_buttons,
+        gamepads: &gamepads,
+        gamepad_buttons: &gamepad_buttons,
+        gamepad_button_axes: &gamepad_button_axes,
+        gamepad_axes: &gamepad_axes,
+    };
+    for command in InputCommand::ALL {
+        if input_map
+            .bindings(command)
+            .iter()
+            .any(|binding| devices.pressed(binding))
+        {
+            event.send(InputEvent(command));
+        }
     }
-    if input.pressed(KeyCode::S) {
-        event.send(InputEvent(InputCommand::DOWN));
+
+    let strength = |command: InputCommand| {
+        input_map
+            .bindings(command)
+            .iter()
+            .map(|binding| devices.strength(binding))
+            .fold(0.0, f32::max)
+    };
+    let raw = Vec2::new(
+        strength(InputCommand::RIGHT) - strength(InputCommand::LEFT),
+        strength(InputCommand::UP) - strength(InputCommand::DOWN),
+    );
+    let movement = apply_dead_zone(raw, input_map.dead_zone);
+    if movement != Vec2::ZERO {
+        move_event.send(MoveEvent(movement));
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct ZoomEvent(pub f32);
 
 pub fn scroll_system(
@@ -60,7 +102,7 @@ pub fn scroll_system(
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct MouseFloorPosition(pub Vec3);
 
 fn my_cursor_system(
@@ -69,11 +111,15 @@ fn my_cursor_system(
     // query to get camera transform
     q_camera: Query<(&Camera, &GlobalTransform)>,
     mut test: Query<&mut Transform, With<TestDebugComponent>>,
+    statics: Query<(&Transform, &StaticCollider), Without<TestDebugComponent>>,
     mut event: EventWriter<MouseFloorPosition>,
 ) {
     // get the camera info and transform
-    // assuming there is exactly one main camera entity, so query::single() is OK
-    let (camera, camera_transform) = q_camera.single();
+    // assuming there is at most one main camera entity, headless apps have none
+    let (camera, camera_transform) = match q_camera.get_single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
 
     // get the window that the camera is displaying to (or the primary window)
     let wnd = if let RenderTarget::Window(id) = camera.target {
@@ -105,16 +151,24 @@ fn my_cursor_system(
         // construct ray from screenpoint in view direction
         let ray_dir = (world_pos - camera_transform.translation()).normalize();
 
-        // find intersection length with y-0-plane
-        let ray_length = (-world_pos.y) / ray_dir.y;
-
-        // find postion on plane
-        let zero_plane_pos = world_pos + ray_length * ray_dir;
-
-        event.send(MouseFloorPosition(zero_plane_pos));
+        // pick the level geometry, fall back to the y-0-plane if the ray misses everything
+        let floor_pos = match raycast_all(
+            world_pos,
+            ray_dir,
+            f32::INFINITY,
+            statics.iter().map(|(t, collider)| (t, &collider.0)),
+        ) {
+            Some(hit) => hit.point,
+            None => {
+                let ray_length = (-world_pos.y) / ray_dir.y;
+                world_pos + ray_length * ray_dir
+            }
+        };
+
+        event.send(MouseFloorPosition(floor_pos));
 
         if let Ok(mut test_transform) = test.get_single_mut() {
-            test_transform.translation = zero_plane_pos;
+            test_transform.translation = floor_pos;
         }
     }
 }
@@ -129,16 +183,38 @@ pub fn debug_input_system(time: Res<Time>, mut events: EventReader<InputEvent>)
     }
 }
 
+// Device driven gameplay input only runs in game and while no replay plays
+fn live_game_input(
+    replay: Option<Res<Replay>>,
+    app_state: Option<Res<State<AppState>>>,
+) -> ShouldRun {
+    match (live_input(replay), in_game(app_state)) {
+        (ShouldRun::Yes, ShouldRun::Yes) => ShouldRun::Yes,
+        _ => ShouldRun::No,
+    }
+}
+
 pub struct InputPlugin;
 
 impl Plugin for InputPlugin {
     fn build(&self, app: &mut App) {
-        app.add_event::<InputEvent>()
+        app.insert_resource(InputMap::load())
+            .init_resource::<PendingRebind>()
+            .init_resource::<ActionState<InputCommand>>()
+            .add_event::<InputEvent>()
+            .add_event::<MoveEvent>()
             .add_event::<MouseFloorPosition>()
             .add_event::<ZoomEvent>()
-            .add_system(input_system.after(InputSystem))
-            .add_system(my_cursor_system)
-            .add_system(scroll_system);
+            .add_event::<RebindEvent>()
+            .add_system(rebind_system.before(input_system))
+            .add_system(
+                input_system
+                    .after(InputSystem)
+                    .with_run_criteria(live_input),
+            )
+            .add_system(action_state_system.after(input_system))
+            .add_system(my_cursor_system.with_run_criteria(live_game_input))
+            .add_system(scroll_system.with_run_criteria(live_game_input));
         // .add_system(debug_input_system.after(input_system));
     }
 
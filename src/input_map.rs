@@ -0,0 +1,379 @@
+use std::{collections::HashMap, fs};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::input::InputCommand;
+
+// Shipped default bindings, relative to the working directory like the asset folder
+pub const DEFAULT_INPUT_MAP_PATH: &str = "assets/input_map.ron";
+// Bindings the user changed at runtime, only contains the rebound commands
+pub const USER_INPUT_MAP_PATH: &str = "input_map.user.ron";
+
+// How far a gamepad axis needs to be pushed to count as pressed
+const AXIS_PRESS_THRESHOLD: f32 = 0.5;
+// Axes that can be captured when rebinding
+const REBINDABLE_AXES: [GamepadAxisType; 6] = [
+    GamepadAxisType::LeftStickX,
+    GamepadAxisType::LeftStickY,
+    GamepadAxisType::LeftZ,
+    GamepadAxisType::RightStickX,
+    GamepadAxisType::RightStickY,
+    GamepadAxisType::RightZ,
+];
+// Cancels a pending rebind instead of being bound
+const REBIND_CANCEL_KEY: KeyCode = KeyCode::Escape;
+// Radial dead zone of the movement vector if the input map doesn't define one
+const DEFAULT_DEAD_ZONE: f32 = 0.15;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum AxisDirection {
+    POSITIVE,
+    NEGATIVE,
+}
+
+// A single physical input that can trigger an InputCommand
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum InputBinding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    GamepadButton(GamepadButtonType),
+    GamepadAxis(GamepadAxisType, AxisDirection),
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct InputMapFile {
+    bindings: HashMap<InputCommand, Vec<InputBinding>>,
+    #[serde(default)]
+    dead_zone: Option<f32>,
+}
+
+impl InputMapFile {
+    fn read(path: &str) -> Option<InputMapFile> {
+        let content = fs::read_to_string(path).ok()?;
+        match ron::from_str(&content) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                warn!("Failed to parse input map {}: {}", path, e);
+                None
+            }
+        }
+    }
+}
+
+pub struct InputMap {
+    defaults: HashMap<InputCommand, Vec<InputBinding>>,
+    overrides: HashMap<InputCommand, Vec<InputBinding>>,
+    pub dead_zone: f32,
+}
+
+impl InputMap {
+    pub fn load() -> InputMap {
+        let default_file = InputMapFile::read(DEFAULT_INPUT_MAP_PATH).unwrap_or_else(|| {
+            warn!(
+                "No valid input map at {}, using built-in bindings",
+                DEFAULT_INPUT_MAP_PATH
+            );
+            InputMapFile {
+                bindings: builtin_bindings(),
+                dead_zone: None,
+            }
+        });
+        let user_file = InputMapFile::read(USER_INPUT_MAP_PATH).unwrap_or_default();
+
+        return InputMap {
+            defaults: default_file.bindings,
+            overrides: user_file.bindings,
+            dead_zone: user_file
+                .dead_zone
+                .or(default_file.dead_zone)
+                .unwrap_or(DEFAULT_DEAD_ZONE),
+        };
+    }
+
+    pub fn bindings(&self, command: InputCommand) -> &[InputBinding] {
+        self.overrides
+            .get(&command)
+            .or_else(|| self.defaults.get(&command))
+            .map(|b| b.as_slice())
+            .unwrap_or(&[])
+    }
+
+    // Replaces the binding at the index of the command, or adds it if the command has fewer
+    // bindings. The binding is taken away from other commands so it never drives two of them
+    pub fn rebind(&mut self, command: InputCommand, index: usize, binding: InputBinding) {
+        let others: Vec<InputCommand> = self
+            .defaults
+            .keys()
+            .chain(self.overrides.keys())
+            .filter(|other| **other != command)
+            .copied()
+            .collect();
+        for other in others {
+            if self.bindings(other).contains(&binding) {
+                let remaining = self
+                    .bindings(other)
+                    .iter()
+                    .filter(|b| **b != binding)
+                    .copied()
+                    .collect();
+                self.overrides.insert(other, remaining);
+            }
+        }
+
+        let mut bindings = self.bindings(command).to_vec();
+        let index = index.min(bindings.len());
+        if index < bindings.len() {
+            bindings[index] = binding;
+        } else {
+            bindings.push(binding);
+        }
+        // Drop an older copy of the binding in another slot
+        let mut position = 0;
+        bindings.retain(|b| {
+            let keep = *b != binding || position == index;
+            position += 1;
+            keep
+        });
+        self.overrides.insert(command, bindings);
+    }
+
+    // Back to the defaults, which can overlap with bindings moved to other commands
+    pub fn reset(&mut self, command: InputCommand) {
+        self.overrides.remove(&command);
+    }
+
+    pub fn save_overrides(&self) {
+        let file = InputMapFile {
+            bindings: self.overrides.clone(),
+            dead_zone: Some(self.dead_zone),
+        };
+        let result = ron::ser::to_string_pretty(&file, ron::ser::PrettyConfig::default())
+            .map_err(|e| e.to_string())
+            .and_then(|s| fs::write(USER_INPUT_MAP_PATH, s).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("Failed to save input map to {}: {}", USER_INPUT_MAP_PATH, e);
+        }
+    }
+}
+
+fn builtin_bindings() -> HashMap<InputCommand, Vec<InputBinding>> {
+    HashMap::from([
+        (
+            InputCommand::LEFT,
+            vec![
+                InputBinding::Key(KeyCode::Left),
+                InputBinding::Key(KeyCode::A),
+            ],
+        ),
+        (
+            InputCommand::RIGHT,
+            vec![
+                InputBinding::Key(KeyCode::Right),
+                InputBinding::Key(KeyCode::D),
+            ],
+        ),
+        (
+            InputCommand::UP,
+            vec![
+                InputBinding::Key(KeyCode::Up),
+                InputBinding::Key(KeyCode::W),
+            ],
+        ),
+        (
+            InputCommand::DOWN,
+            vec![
+                InputBinding::Key(KeyCode::Down),
+                InputBinding::Key(KeyCode::S),
+            ],
+        ),
+        (
+            InputCommand::ACTION,
+            vec![InputBinding::Key(KeyCode::Space)],
+        ),
+    ])
+}
+
+// Borrowed device state for the current frame
+pub struct InputDevices<'a> {
+    pub keys: &'a Input<KeyCode>,
+    pub mouse_buttons: &'a Input<MouseButton>,
+    pub gamepads: &'a Gamepads,
+    pub gamepad_buttons: &'a Input<GamepadButton>,
+    pub gamepad_button_axes: &'a Axis<GamepadButton>,
+    pub gamepad_axes: &'a Axis<GamepadAxis>,
+}
+
+impl<'a> InputDevices<'a> {
+    pub fn pressed(&self, binding: &InputBinding) -> bool {
+        match *binding {
+            InputBinding::Key(key) => self.keys.pressed(key),
+            InputBinding::Mouse(button) => self.mouse_buttons.pressed(button),
+            InputBinding::GamepadButton(button_type) => self.gamepads.iter().any(|gamepad| {
+                self.gamepad_buttons
+                    .pressed(GamepadButton::new(*gamepad, button_type))
+            }),
+            InputBinding::GamepadAxis(axis_type, direction) => {
+                self.gamepads.iter().any(|gamepad| {
+                    let value = self
+                        .gamepad_axes
+                        .get(GamepadAxis::new(*gamepad, axis_type))
+                        .unwrap_or(0.0);
+                    match direction {
+                        AxisDirection::POSITIVE => value >= AXIS_PRESS_THRESHOLD,
+                        AxisDirection::NEGATIVE => value <= -AXIS_PRESS_THRESHOLD,
+                    }
+                })
+            }
+        }
+    }
+
+    // Analog value of the binding in 0..1, digital inputs are either 0 or 1
+    pub fn strength(&self, binding: &InputBinding) -> f32 {
+        match *binding {
+            InputBinding::Key(_) | InputBinding::Mouse(_) => {
+                if self.pressed(binding) {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            // Triggers report their analog value on the button axis
+            InputBinding::GamepadButton(button_type) => self
+                .gamepads
+                .iter()
+                .map(|gamepad| {
+                    let button = GamepadButton::new(*gamepad, button_type);
+                    let digital = if self.gamepad_buttons.pressed(button) {
+                        1.0
+                    } else {
+                        0.0
+                    };
+                    let analog = self.gamepad_button_axes.get(button).unwrap_or(0.0);
+                    analog.max(digital)
+                })
+                .fold(0.0, f32::max),
+            InputBinding::GamepadAxis(axis_type, direction) => self
+                .gamepads
+                .iter()
+                .map(|gamepad| {
+                    let value = self
+                        .gamepad_axes
+                        .get(GamepadAxis::new(*gamepad, axis_type))
+                        .unwrap_or(0.0);
+                    match direction {
+                        AxisDirection::POSITIVE => value.clamp(0.0, 1.0),
+                        AxisDirection::NEGATIVE => (-value).clamp(0.0, 1.0),
+                    }
+                })
+                .fold(0.0, f32::max),
+        }
+    }
+
+    // The first binding that was pressed this frame, used to capture rebinds
+    pub fn just_pressed_binding(&self) -> Option<InputBinding> {
+        if let Some(key) = self.keys.get_just_pressed().next() {
+            return Some(InputBinding::Key(*key));
+        }
+        if let Some(button) = self.mouse_buttons.get_just_pressed().next() {
+            return Some(InputBinding::Mouse(*button));
+        }
+        if let Some(button) = self.gamepad_buttons.get_just_pressed().next() {
+            return Some(InputBinding::GamepadButton(button.button_type));
+        }
+        None
+    }
+
+    // An axis pushed past the press threshold on any gamepad, used to capture rebinds
+    pub fn pushed_axis(&self) -> Option<InputBinding> {
+        for gamepad in self.gamepads.iter() {
+            for axis_type in REBINDABLE_AXES {
+                let value = self
+                    .gamepad_axes
+                    .get(GamepadAxis::new(*gamepad, axis_type))
+                    .unwrap_or(0.0);
+                if value >= AXIS_PRESS_THRESHOLD {
+                    return Some(InputBinding::GamepadAxis(
+                        axis_type,
+                        AxisDirection::POSITIVE,
+                    ));
+                }
+                if value <= -AXIS_PRESS_THRESHOLD {
+                    return Some(InputBinding::GamepadAxis(
+                        axis_type,
+                        AxisDirection::NEGATIVE,
+                    ));
+                }
+            }
+        }
+        None
+    }
+}
+
+// Send this to bind the next pressed input to the command, replacing its binding at the index
+#[derive(Clone, Copy, Debug)]
+pub struct RebindEvent {
+    pub command: InputCommand,
+    pub index: usize,
+}
+
+#[derive(Default)]
+pub struct PendingRebind(pub Option<RebindEvent>);
+
+pub fn rebind_system(
+    mut rebind_events: EventReader<RebindEvent>,
+    mut pending: ResMut<PendingRebind>,
+    mut input_map: ResMut<InputMap>,
+    mut keys: ResMut<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    gamepads: Res<Gamepads>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    gamepad_button_axes: Res<Axis<GamepadButton>>,
+    gamepad_axes: Res<Axis<GamepadAxis>>,
+    mut last_pushed_axis: Local<Option<InputBinding>>,
+) {
+    let devices = InputDevices {
+        keys: &keys,
+        mouse_buttons: &mouse_buttons,
+        gamepads: &gamepads,
+        gamepad_buttons: &gamepad_buttons,
+        gamepad_button_axes: &gamepad_button_axes,
+        gamepad_axes: &gamepad_axes,
+    };
+    // Axes only count when they cross the threshold, not while they stay pushed
+    let pushed_axis = devices.pushed_axis();
+    let newly_pushed_axis = pushed_axis.filter(|axis| Some(*axis) != *last_pushed_axis);
+    *last_pushed_axis = pushed_axis;
+
+    if let Some(e) = rebind_events.iter().last() {
+        pending.0 = Some(*e);
+        // Don't capture the input that requested the rebind
+        return;
+    }
+
+    if let Some(rebind) = pending.0 {
+        if keys.just_pressed(REBIND_CANCEL_KEY) {
+            info!("Cancelled rebinding {:?}", rebind.command);
+            pending.0 = None;
+            // Nothing else reacts to the cancelling press
+            keys.reset(REBIND_CANCEL_KEY);
+            return;
+        }
+        if let Some(binding) = devices.just_pressed_binding().or(newly_pushed_axis) {
+            info!("Bound {:?} to {:?}", binding, rebind.command);
+            input_map.rebind(rebind.command, rebind.index, binding);
+            input_map.save_overrides();
+            pending.0 = None;
+        }
+    }
+}
+
+// Rescales the vector so movement starts at the edge of the dead zone and is at most 1 long
+pub fn apply_dead_zone(raw: Vec2, dead_zone: f32) -> Vec2 {
+    let magnitude = raw.length();
+    if magnitude <= dead_zone {
+        return Vec2::ZERO;
+    }
+    let scaled = (magnitude.min(1.0) - dead_zone) / (1.0 - dead_zone);
+    raw / magnitude * scaled
+}
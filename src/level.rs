@@ -0,0 +1,585 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    f32::consts::PI,
+    fs,
+};
+
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::{
+    ai::AiController,
+    animation::Animations,
+    app_state::AppState,
+    camera::CameraZoom,
+    collision::{ColliderShape, CollisionBounds, StaticCollider},
+    enemy::EnemyBundle,
+    material_override::{MaterialOverride, MaterialOverrides},
+    navigation::{NavGridSettings, NavObstacle},
+    player::PlayerBundle,
+    save::Persistent,
+    test_scene::NormalVisualizerMaterial,
+    texture_tiling::{TextureTiling, TileableTextures},
+    NameV2,
+};
+
+pub const LEVEL_PATH: &str = "assets/levels/test.ron";
+
+// Everything in the world, loaded from a RON file like assets/levels/test.ron
+#[derive(Debug, Deserialize)]
+pub struct Level {
+    #[serde(default)]
+    pub ambient_light: Option<LevelAmbientLight>,
+    // Loaded before the level starts in addition to the assets the objects reference
+    #[serde(default)]
+    pub assets: Vec<String>,
+    #[serde(default)]
+    pub bounds: LevelBounds,
+    pub objects: Vec<LevelObject>,
+}
+
+// Area on the xz plane that characters are kept in and the navigation grid covers
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct LevelBounds {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+impl Default for LevelBounds {
+    fn default() -> Self {
+        return Self {
+            min: Vec2::new(-10.0, -10.0),
+            max: Vec2::new(10.0, 10.0),
+        };
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LevelAmbientLight {
+    pub color: Color,
+    pub brightness: f32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LevelObject {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub transform: LevelTransform,
+    pub kind: LevelObjectKind,
+    // Only on top level objects, collision uses the local transform
+    #[serde(default)]
+    pub collider: Option<LevelCollider>,
+    // Blocks the navigation grid, needs a collider
+    #[serde(default)]
+    pub nav_obstacle: bool,
+    // Textures of tiled meshes get a repeating sampler
+    #[serde(default)]
+    pub texture_tiling: Option<TextureTiling>,
+    #[serde(default)]
+    pub children: Vec<LevelObject>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct LevelTransform {
+    pub translation: Vec3,
+    // Euler angles in degrees, applied in XYZ order
+    pub rotation: Vec3,
+    pub scale: Vec3,
+    // Overrides the rotation, e.g. for cameras
+    pub looking_at: Option<Vec3>,
+}
+
+impl Default for LevelTransform {
+    fn default() -> Self {
+        return Self {
+            translation: Vec3::ZERO,
+            rotation: Vec3::ZERO,
+            scale: Vec3::ONE,
+            looking_at: None,
+        };
+    }
+}
+
+impl LevelTransform {
+    pub fn to_transform(&self) -> Transform {
+        let rotation = self.rotation * PI / 180.0;
+        let transform = Transform {
+            translation: self.translation,
+            rotation: Quat::from_euler(EulerRot::XYZ, rotation.x, rotation.y, rotation.z),
+            scale: self.scale,
+        };
+        match self.looking_at {
+            Some(target) => transform.looking_at(target, Vec3::Y),
+            None => transform,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub enum LevelObjectKind {
+    // Scene 0 of the glTF file, materials are overrides by glTF material name
+    PLAYER {
+        gltf: String,
+        animation_graph: String,
+        #[serde(default)]
+        materials: HashMap<String, LevelMaterial>,
+    },
+    ENEMY {
+        gltf: String,
+        animation_graph: String,
+        ai_profile: String,
+    },
+    MESH {
+        mesh: LevelMesh,
+        material: LevelMaterial,
+    },
+    LIGHT(LevelLight),
+    CAMERA,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum LevelMesh {
+    PLANE { size: f32 },
+    CUBE { size: f32 },
+    SPHERE { radius: f32 },
+}
+
+#[derive(Debug, Deserialize)]
+pub enum LevelMaterial {
+    STANDARD {
+        color: Color,
+        #[serde(default)]
+        base_color_texture: Option<String>,
+        #[serde(default)]
+        normal_map_texture: Option<String>,
+        #[serde(default = "default_roughness")]
+        perceptual_roughness: f32,
+        #[serde(default)]
+        metallic: f32,
+    },
+    VISUALIZER {
+        color_a: Color,
+        color_b: Color,
+        intensity: f32,
+    },
+}
+
+fn default_roughness() -> f32 {
+    0.5
+}
+
+#[derive(Debug, Deserialize)]
+pub enum LevelLight {
+    POINT {
+        color: Color,
+        intensity: f32,
+        #[serde(default)]
+        shadows: bool,
+    },
+    // Shadows cover a square of twice the half size around the origin
+    DIRECTIONAL {
+        illuminance: f32,
+        shadows: bool,
+        shadow_half_size: f32,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum LevelCollider {
+    BOX { half_extents: Vec3 },
+    SPHERE { radius: f32 },
+    // Uses the triangles of the object's mesh
+    MESH,
+}
+
+// Spawned from the level file, the root of everything that belongs to the level
+#[derive(Component)]
+pub struct LevelEntity;
+
+impl Level {
+    pub fn load(path: &str) -> Result<Level, String> {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        ron::from_str(&content).map_err(|e| e.to_string())
+    }
+
+    pub fn object(&self, name: &str) -> Option<&LevelObject> {
+        self.objects
+            .iter()
+            .find(|object| object.name.as_deref() == Some(name))
+    }
+
+    // Every asset path the level needs, each one once
+    pub fn required_assets(&self) -> Vec<String> {
+        let mut paths: BTreeSet<String> = self.assets.iter().cloned().collect();
+        for object in self.objects.iter() {
+            object.collect_assets(&mut paths);
+        }
+        paths.into_iter().collect()
+    }
+}
+
+impl LevelObject {
+    fn collect_assets(&self, paths: &mut BTreeSet<String>) {
+        match &self.kind {
+            LevelObjectKind::PLAYER {
+                gltf,
+                animation_graph,
+                materials,
+            } => {
+                paths.insert(gltf.clone());
+                paths.insert(animation_graph.clone());
+                for material in materials.values() {
+                    material.collect_assets(paths);
+                }
+            }
+            LevelObjectKind::ENEMY {
+                gltf,
+                animation_graph,
+                ..
+            } => {
+                paths.insert(gltf.clone());
+                paths.insert(animation_graph.clone());
+            }
+            LevelObjectKind::MESH { material, .. } => material.collect_assets(paths),
+            LevelObjectKind::LIGHT(_) | LevelObjectKind::CAMERA => {}
+        }
+        for child in self.children.iter() {
+            child.collect_assets(paths);
+        }
+    }
+}
+
+impl LevelMaterial {
+    fn collect_assets(&self, paths: &mut BTreeSet<String>) {
+        if let LevelMaterial::STANDARD {
+            base_color_texture,
+            normal_map_texture,
+            ..
+        } = self
+        {
+            paths.extend(base_color_texture.iter().cloned());
+            paths.extend(normal_map_texture.iter().cloned());
+        }
+    }
+}
+
+// Spawns level objects, also used to bring back characters from a save game
+pub struct LevelSpawner<'a, 'w, 's> {
+    pub commands: Commands<'w, 's>,
+    pub asset_server: &'a AssetServer,
+    pub meshes: &'a mut Assets<Mesh>,
+    pub materials: &'a mut Assets<StandardMaterial>,
+    pub custom_materials: &'a mut Assets<NormalVisualizerMaterial>,
+    // Textures of tiled objects spawned so far
+    pub tileable_textures: Vec<Handle<Image>>,
+}
+
+impl<'a, 'w, 's> LevelSpawner<'a, 'w, 's> {
+    fn material(&mut self, material: &LevelMaterial, tiled: bool) -> MaterialOverride {
+        match material {
+            LevelMaterial::STANDARD {
+                color,
+                base_color_texture,
+                normal_map_texture,
+                perceptual_roughness,
+                metallic,
+            } => {
+                let base_color_texture: Option<Handle<Image>> = base_color_texture
+                    .as_ref()
+                    .map(|p| self.asset_server.load(p.as_str()));
+                let normal_map_texture: Option<Handle<Image>> = normal_map_texture
+                    .as_ref()
+                    .map(|p| self.asset_server.load(p.as_str()));
+                if tiled {
+                    self.tileable_textures.extend(base_color_texture.clone());
+                    self.tileable_textures.extend(normal_map_texture.clone());
+                }
+                MaterialOverride::STANDARD(self.materials.add(StandardMaterial {
+                    base_color: *color,
+                    base_color_texture,
+                    normal_map_texture,
+                    perceptual_roughness: *perceptual_roughness,
+                    metallic: *metallic,
+                    ..default()
+                }))
+            }
+            LevelMaterial::VISUALIZER {
+                color_a,
+                color_b,
+                intensity,
+            } => {
+                MaterialOverride::VISUALIZER(self.custom_materials.add(NormalVisualizerMaterial {
+                    color_a: *color_a,
+                    color_b: *color_b,
+                    intensity: *intensity,
+                }))
+            }
+        }
+    }
+
+    pub fn spawn(&mut self, object: &LevelObject, parent: Option<Entity>) -> Entity {
+        let transform = object.transform.to_transform();
+        let name = object.name.clone().unwrap_or_else(|| "unknown".to_string());
+        let persistent_name = name.clone();
+        let mut mesh_collider = None;
+
+        let entity = match &object.kind {
+            LevelObjectKind::PLAYER {
+                gltf,
+                animation_graph,
+                materials,
+            } => {
+                let overrides = materials
+                    .iter()
+                    .map(|(name, material)| (name.clone(), self.material(material, false)))
+                    .collect();
+                self.commands
+                    .spawn_bundle(PlayerBundle {
+                        name: NameV2(name),
+                        animations: Animations {
+                            gltf: self.asset_server.load(gltf.as_str()),
+                            graph: self.asset_server.load(animation_graph.as_str()),
+                        },
+                        scene_bundle: SceneBundle {
+                            scene: self.asset_server.load(format!("{}#Scene0", gltf).as_str()),
+                            transform,
+                            ..default()
+                        },
+                        ..PlayerBundle::default()
+                    })
+                    .insert(Persistent(persistent_name))
+                    .insert(MaterialOverrides {
+                        gltf: self.asset_server.load(gltf.as_str()),
+                        materials: overrides,
+                        ..default()
+                    })
+                    .id()
+            }
+            LevelObjectKind::ENEMY {
+                gltf,
+                animation_graph,
+                ai_profile,
+            } => self
+                .commands
+                .spawn_bundle(EnemyBundle {
+                    name: NameV2(name),
+                    ai: AiController::new(ai_profile),
+                    animations: Animations {
+                        gltf: self.asset_server.load(gltf.as_str()),
+                        graph: self.asset_server.load(animation_graph.as_str()),
+                    },
+                    scene_bundle: SceneBundle {
+                        scene: self.asset_server.load(format!("{}#Scene0", gltf).as_str()),
+                        transform,
+                        ..default()
+                    },
+                    ..EnemyBundle::default()
+                })
+                .insert(Persistent(persistent_name))
+                .id(),
+            LevelObjectKind::MESH { mesh, material } => {
+                let mesh = match *mesh {
+                    LevelMesh::PLANE { size } => Mesh::from(shape::Plane { size }),
+                    LevelMesh::CUBE { size } => Mesh::from(shape::Cube { size }),
+                    LevelMesh::SPHERE { radius } => Mesh::from(shape::Icosphere {
+                        radius,
+                        ..default()
+                    }),
+                };
+                if let Some(LevelCollider::MESH) = object.collider {
+                    mesh_collider = ColliderShape::from_mesh(&mesh);
+                }
+                let mesh = self.meshes.add(mesh);
+                let material = self.material(material, object.texture_tiling.is_some());
+                let mut entity = match material {
+                    MaterialOverride::STANDARD(material) => {
+                        self.commands.spawn_bundle(MaterialMeshBundle {
+                            mesh,
+                            material,
+                            transform,
+                            ..default()
+                        })
+                    }
+                    MaterialOverride::VISUALIZER(material) => {
+                        self.commands.spawn_bundle(MaterialMeshBundle {
+                            mesh,
+                            material,
+                            transform,
+                            ..default()
+                        })
+                    }
+                };
+                entity.insert(NameV2(name));
+                entity.id()
+            }
+            LevelObjectKind::LIGHT(LevelLight::POINT {
+                color,
+                intensity,
+                shadows,
+            }) => self
+                .commands
+                .spawn_bundle(PointLightBundle {
+                    point_light: PointLight {
+                        color: *color,
+                        intensity: *intensity,
+                        shadows_enabled: *shadows,
+                        ..default()
+                    },
+                    transform,
+                    ..default()
+                })
+                .insert(NameV2(name))
+                .id(),
+            LevelObjectKind::LIGHT(LevelLight::DIRECTIONAL {
+                illuminance,
+                shadows,
+                shadow_half_size,
+            }) => self
+                .commands
+                .spawn_bundle(DirectionalLightBundle {
+                    directional_light: DirectionalLight {
+                        illuminance: *illuminance,
+                        shadows_enabled: *shadows,
+                        shadow_projection: OrthographicProjection {
+                            left: -shadow_half_size,
+                            right: *shadow_half_size,
+                            bottom: -shadow_half_size,
+                            top: *shadow_half_size,
+                            near: -10.0 * shadow_half_size,
+                            far: 10.0 * shadow_half_size,
+                            ..default()
+                        },
+                        ..default()
+                    },
+                    transform,
+                    ..default()
+                })
+                .insert(NameV2(name))
+                .id(),
+            LevelObjectKind::CAMERA => self
+                .commands
+                .spawn_bundle(Camera3dBundle {
+                    transform,
+                    ..default()
+                })
+                .insert(NameV2(name))
+                .id(),
+        };
+
+        let mut entity_commands = self.commands.entity(entity);
+        if let Some(tiling) = &object.texture_tiling {
+            entity_commands.insert(tiling.clone());
+        }
+        let collider = match object.collider {
+            Some(LevelCollider::BOX { half_extents }) => Some(ColliderShape::Box { half_extents }),
+            Some(LevelCollider::SPHERE { radius }) => Some(ColliderShape::Sphere { radius }),
+            Some(LevelCollider::MESH) => {
+                if mesh_collider.is_none() {
+                    warn!("Mesh collider of {:?} needs a mesh object", object.name);
+                }
+                mesh_collider
+            }
+            None => None,
+        };
+        if parent.is_some() && collider.is_some() {
+            warn!(
+                "Ignoring the collider of {:?}, children can't have colliders",
+                object.name
+            );
+        } else {
+            if let Some(collider) = collider {
+                entity_commands.insert(StaticCollider(collider));
+            }
+            if object.nav_obstacle {
+                entity_commands.insert(NavObstacle);
+            }
+        }
+        match parent {
+            Some(parent) => {
+                self.commands.entity(parent).add_child(entity);
+            }
+            None => {
+                self.commands.entity(entity).insert(LevelEntity);
+            }
+        }
+
+        for child in object.children.iter() {
+            self.spawn(child, Some(entity));
+        }
+        entity
+    }
+}
+
+pub fn level_spawn_system(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut custom_materials: ResMut<Assets<NormalVisualizerMaterial>>,
+    mut collision_bounds: ResMut<CollisionBounds>,
+    mut nav_settings: ResMut<NavGridSettings>,
+) {
+    let level = match Level::load(LEVEL_PATH) {
+        Ok(level) => level,
+        Err(e) => {
+            error!("Failed to load level from {}: {}", LEVEL_PATH, e);
+            return;
+        }
+    };
+
+    collision_bounds.min = level.bounds.min;
+    collision_bounds.max = level.bounds.max;
+    // Rebuilds the navigation grid
+    nav_settings.min = level.bounds.min;
+    nav_settings.max = level.bounds.max;
+
+    if let Some(ambient_light) = &level.ambient_light {
+        commands.insert_resource(AmbientLight {
+            color: ambient_light.color,
+            brightness: ambient_light.brightness,
+        });
+    }
+
+    let mut spawner = LevelSpawner {
+        commands,
+        asset_server: &asset_server,
+        meshes: &mut meshes,
+        materials: &mut materials,
+        custom_materials: &mut custom_materials,
+        tileable_textures: Vec::new(),
+    };
+    for object in level.objects.iter() {
+        spawner.spawn(object, None);
+    }
+    let tileable_textures = TileableTextures(spawner.tileable_textures);
+    spawner.commands.insert_resource(tileable_textures);
+}
+
+fn level_teardown_system(
+    mut commands: Commands,
+    mut zoom: ResMut<CameraZoom>,
+    query: Query<Entity, With<LevelEntity>>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    zoom.0 = 0.0;
+}
+
+pub struct LevelPlugin;
+
+impl Plugin for LevelPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(TileableTextures(Vec::new()))
+            .add_system_set(SystemSet::on_enter(AppState::PLAYING).with_system(level_spawn_system))
+            .add_system_set(
+                SystemSet::on_exit(AppState::PLAYING).with_system(level_teardown_system),
+            );
+    }
+
+    fn name(&self) -> &str {
+        "LevelPlugin"
+    }
+}
@@ -1,24 +1,55 @@
-use animation::player_animation_system;
+use animation::{animation_graph_system, AnimationGraphPlugin};
 use bevy::prelude::*;
 
+use ai::AiPlugin;
+use app_state::{in_game, AppStatePlugin};
 use bevy::render::texture::ImageSettings;
+use bevy::transform::TransformSystem;
 use bevy_inspector_egui::prelude::*;
-use camera::camera_follow_player_system;
-use input::input_system;
-use movement::{player_movement_system, MovementSpeed};
+use camera::{camera_follow_player_system, CameraZoom};
+use collision::CollisionPlugin;
+use combat::CombatPlugin;
+use enemy::enemy_animation_parameters_system;
+use level::LevelPlugin;
+use material_override::MaterialOverridePlugin;
+use movement::MovementPlugin;
+use navigation::NavigationPlugin;
+use player::{player_animation_parameters_system, player_state_system};
+use root_motion::RootMotionPlugin;
+use save::SavePlugin;
+use scene_ready::SceneReadyPlugin;
+use simulation::{SimulationPlugin, SimulationStage, SimulationSystem};
+use steering::SteeringPlugin;
 use test_scene::TestScencePlugin;
 use texture_tiling::TextureTilingPlugin;
 
 use crate::input::InputPlugin;
+use crate::replay::ReplayPlugin;
 
+mod action_state;
+mod ai;
 mod animation;
+mod app_state;
+mod asset_manifest;
 mod camera;
+mod collision;
+mod combat;
+mod enemy;
 mod input;
+mod input_map;
+mod level;
+mod material_override;
 mod movement;
+mod navigation;
 mod player;
+mod replay;
+mod root_motion;
+mod save;
+mod scene_ready;
+mod simulation;
+mod steering;
 mod test_scene;
 mod texture_tiling;
-mod enemy;
 
 mod debug;
 
@@ -32,12 +63,40 @@ fn main() {
         .insert_resource(ImageSettings::default_linear())
         .add_plugins(DefaultPlugins)
         .add_plugin(WorldInspectorPlugin::new())
+        .init_resource::<CameraZoom>()
+        .add_plugin(SimulationPlugin)
+        .add_plugin(AppStatePlugin)
+        .add_plugin(SceneReadyPlugin)
         .add_plugin(TestScencePlugin)
+        .add_plugin(LevelPlugin)
         .add_plugin(InputPlugin)
+        .add_plugin(ReplayPlugin)
+        .add_plugin(SavePlugin)
         .add_plugin(TextureTilingPlugin)
-        .add_system(player_movement_system.after(input_system))
-        .add_system(player_animation_system.after(player_movement_system))
-        .add_system(camera_follow_player_system)
-        .add_system(bevy::window::close_on_esc)
+        .add_plugin(NavigationPlugin)
+        .add_plugin(CollisionPlugin)
+        .add_plugin(AiPlugin)
+        .add_plugin(MovementPlugin)
+        .add_plugin(SteeringPlugin)
+        .add_plugin(CombatPlugin)
+        .add_plugin(AnimationGraphPlugin)
+        .add_plugin(RootMotionPlugin)
+        .add_plugin(MaterialOverridePlugin)
+        .add_system_to_stage(
+            SimulationStage,
+            player_state_system
+                .after(SimulationSystem::MOVEMENT)
+                .before(SimulationSystem::FINALIZE),
+        )
+        .add_system(player_animation_parameters_system.before(animation_graph_system))
+        .add_system(enemy_animation_parameters_system.before(animation_graph_system))
+        // The camera follows the interpolated player, so it runs after the interpolation
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            camera_follow_player_system
+                .after(SimulationSystem::INTERPOLATE)
+                .before(TransformSystem::TransformPropagate)
+                .with_run_criteria(in_game),
+        )
         .run();
 }
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+use bevy::{gltf::Gltf, prelude::*, scene::SceneInstance};
+
+use crate::{
+    scene_ready::{SceneReady, SceneReadyEvent},
+    test_scene::NormalVisualizerMaterial,
+};
+
+#[derive(Clone)]
+pub enum MaterialOverride {
+    STANDARD(Handle<StandardMaterial>),
+    VISUALIZER(Handle<NormalVisualizerMaterial>),
+}
+
+// Replaces materials of the scene spawned by the SceneBundle, meshes are matched by their
+// glTF material name first, then by the name of their node or its ancestors
+#[derive(Component, Default)]
+pub struct MaterialOverrides {
+    // The glTF file of the scene, needed to know the material names
+    pub gltf: Handle<Gltf>,
+    pub materials: HashMap<String, MaterialOverride>,
+    pub nodes: HashMap<String, MaterialOverride>,
+    // Used for meshes that match nothing else, keeps their material if not set
+    pub fallback: Option<MaterialOverride>,
+}
+
+// The glTF material of a mesh, kept so overrides still match after replacing it
+#[derive(Component)]
+pub struct OriginalMaterial(pub Handle<StandardMaterial>);
+
+fn node_override<'a>(
+    overrides: &'a MaterialOverrides,
+    entity: Entity,
+    root: Entity,
+    names: &Query<&Name>,
+    parents: &Query<&Parent>,
+) -> Option<&'a MaterialOverride> {
+    let mut current = entity;
+    loop {
+        if let Some(material) = names
+            .get(current)
+            .ok()
+            .and_then(|name| overrides.nodes.get(name.as_str()))
+        {
+            return Some(material);
+        }
+        current = parents.get(current).ok()?.get();
+        if current == root {
+            return None;
+        }
+    }
+}
+
+// Applies the overrides when the scene is ready and again whenever they change
+pub fn material_override_system(
+    mut commands: Commands,
+    mut ready_events: EventReader<SceneReadyEvent>,
+    scene_spawner: Res<SceneSpawner>,
+    gltfs: Res<Assets<Gltf>>,
+    scenes: Query<(&SceneInstance, &MaterialOverrides)>,
+    changed: Query<Entity, (Changed<MaterialOverrides>, With<SceneReady>)>,
+    meshes: Query<(Option<&Handle<StandardMaterial>>, Option<&OriginalMaterial>)>,
+    names: Query<&Name>,
+    parents: Query<&Parent>,
+) {
+    let mut roots: Vec<Entity> = ready_events.iter().map(|e| e.root).collect();
+    roots.extend(changed.iter());
+    roots.dedup();
+
+    for root in roots {
+        let (instance, overrides) = match scenes.get(root) {
+            Ok(scene) => scene,
+            Err(_) => continue,
+        };
+        let gltf = match gltfs.get(&overrides.gltf) {
+            Some(gltf) => gltf,
+            None => continue,
+        };
+        let material_names: HashMap<&Handle<StandardMaterial>, &String> = gltf
+            .named_materials
+            .iter()
+            .map(|(name, handle)| (handle, name))
+            .collect();
+
+        let entities = match scene_spawner.iter_instance_entities(**instance) {
+            Some(entities) => entities,
+            None => continue,
+        };
+        for entity in entities {
+            let material = match meshes.get(entity) {
+                Ok((_, Some(original))) => original.0.clone(),
+                Ok((Some(material), None)) => {
+                    commands
+                        .entity(entity)
+                        .insert(OriginalMaterial(material.clone()));
+                    material.clone()
+                }
+                _ => continue,
+            };
+            let replacement = material_names
+                .get(&material)
+                .and_then(|name| overrides.materials.get(name.as_str()))
+                .or_else(|| node_override(overrides, entity, root, &names, &parents))
+                .or(overrides.fallback.as_ref());
+
+            match replacement {
+                Some(MaterialOverride::STANDARD(handle)) => {
+                    commands
+                        .entity(entity)
+                        .remove::<Handle<NormalVisualizerMaterial>>()
+                        .insert(handle.clone());
+                }
+                Some(MaterialOverride::VISUALIZER(handle)) => {
+                    commands
+                        .entity(entity)
+                        .remove::<Handle<StandardMaterial>>()
+                        .insert(handle.clone());
+                }
+                // No longer overridden
+                None => {
+                    commands
+                        .entity(entity)
+                        .remove::<Handle<NormalVisualizerMaterial>>()
+                        .insert(material);
+                }
+            }
+        }
+    }
+}
+
+pub struct MaterialOverridePlugin;
+
+impl Plugin for MaterialOverridePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(material_override_system);
+    }
+
+    fn name(&self) -> &str {
+        "MaterialOverridePlugin"
+    }
+}
@@ -1,98 +1,227 @@
+use std::collections::VecDeque;
+
 use bevy::prelude::*;
 
 use crate::{
-    input::{InputCommand, InputEvent, MouseFloorPosition},
-    player::{Player, PlayerState, PlayerStateEnum},
+    app_state::in_game,
+    input::{input_system, MouseFloorPosition, MoveEvent},
+    navigation::NavAgent,
+    root_motion::RootMotion,
+    simulation::{SimulationStage, SimulationSystem, SIMULATION_TIMESTEP},
+    steering::{arrive, seek, SteeringAgent},
 };
 
+// Targets closer than this count as reached
+const ARRIVAL_DISTANCE: f32 = 0.02;
+
+// Top speed of the character controller
 #[derive(Component)]
 pub struct MovementSpeed(pub f32);
 
 #[derive(Component)]
 pub struct MovementTarget {
     pub current_target: Option<Vec3>,
+    // Followed in order once the current target is reached
+    pub waypoints: VecDeque<Vec3>,
 }
 
 impl Default for MovementTarget {
     fn default() -> Self {
         return Self {
             current_target: Some(Vec3::ZERO),
+            waypoints: VecDeque::new(),
         };
     }
 }
 
-pub fn player_movement_system(
-    mut input_event: EventReader<InputEvent>,
+// Where the entity wants to go, length is in 0..1 of its MovementSpeed. Written by the
+// player input, by target following for AI and paths, or by the network
+#[derive(Component, Default)]
+pub struct MovementIntent {
+    pub direction: Vec3,
+}
+
+// Entities whose intent comes from the local input devices
+#[derive(Component)]
+pub struct PlayerControlled;
+
+#[derive(Component)]
+pub struct CharacterController {
+    pub desired_velocity: Vec3,
+    pub velocity: Vec3,
+    // Units per second squared the velocity changes towards the desired velocity
+    pub acceleration: f32,
+    // Radians per second
+    pub turn_rate: f32,
+    pub target_rotation: Quat,
+}
+
+impl Default for CharacterController {
+    fn default() -> Self {
+        return Self {
+            desired_velocity: Vec3::ZERO,
+            velocity: Vec3::ZERO,
+            acceleration: 40.0,
+            turn_rate: 15.0,
+            target_rotation: Quat::IDENTITY,
+        };
+    }
+}
+
+// Rotation around y that makes a character face along the direction, characters face +z
+pub fn facing_rotation(direction: Vec3) -> Quat {
+    let angle = direction.angle_between(Vec3::Z);
+    Quat::from_rotation_y(if direction.x > 0.0 { angle } else { -angle })
+}
+
+pub fn player_input_system(
+    mut move_event: EventReader<MoveEvent>,
     mut mouse_event: EventReader<MouseFloorPosition>,
-    mut mouse_button_event: Res<Input<MouseButton>>,
+    mouse_button_event: Res<Input<MouseButton>>,
     mut query: Query<
-        (
-            &mut Transform,
-            &MovementSpeed,
-            &mut PlayerState,
-            &mut MovementTarget,
-        ),
-        With<Player>,
+        (&mut MovementIntent, &mut MovementTarget, &mut NavAgent),
+        With<PlayerControlled>,
     >,
-    time: Res<Time>,
-    mut target_rot: Local<Quat>,
 ) {
-    let turn_speed: f32 = 15.0;
-    for (mut transform, speed, mut state, mut target) in query.iter_mut() {
-        let mut direction = Vec3::default();
-        for event in input_event.iter() {
-            match event.0 {
-                InputCommand::RIGHT => direction.x += 1.0,
-                InputCommand::LEFT => direction.x -= 1.0,
-                InputCommand::UP => direction.z -= 1.0,
-                InputCommand::DOWN => direction.z += 1.0,
-                InputCommand::ACTION => (),
-            }
-        }
+    let mut direction = Vec3::default();
+    for event in move_event.iter() {
+        direction = Vec3::new(event.0.x, 0.0, -event.0.y);
+    }
+    let mouse_positions: Vec<Vec3> = mouse_event.iter().map(|e| e.0).collect();
+
+    for (mut intent, mut target, mut agent) in query.iter_mut() {
+        intent.direction = direction;
         if direction.length() > 0.0 {
             target.current_target = None;
+            target.waypoints.clear();
+            if agent.destination.is_some() {
+                agent.destination = None;
+            }
         }
 
         if mouse_button_event.pressed(MouseButton::Right) {
-            for event in mouse_event.iter() {
-                if let Some(t) = target.current_target {
-                    if (t - event.0).length() >= 0.1 {
-                        target.current_target = Some(event.0);
+            for position in mouse_positions.iter() {
+                if let Some(t) = agent.destination {
+                    if (t - *position).length() >= 0.1 {
+                        agent.destination = Some(*position);
                     }
                 } else {
-                    target.current_target = Some(event.0);
+                    agent.destination = Some(*position);
                 }
             }
         }
-        if let Some(current_target) = target.current_target {
-            direction = current_target - transform.translation;
-        }
+    }
+}
+
+// Turns the current target into an intent, moving on to the next waypoint on arrival
+pub fn target_follow_system(
+    mut query: Query<(
+        &Transform,
+        &MovementSpeed,
+        &mut MovementTarget,
+        &mut MovementIntent,
+    )>,
+) {
+    for (transform, speed, mut target, mut intent) in query.iter_mut() {
+        let current_target = match target.current_target {
+            Some(current_target) => current_target,
+            None => continue,
+        };
+        // Height is left to the ground snapping
+        let mut offset = current_target - transform.translation;
+        offset.y = 0.0;
+        let distance = offset.length();
 
-        if direction.length() > time.delta_seconds() * speed.0 {
-            let normalized_dir = direction.normalize();
-            transform.translation += normalized_dir * speed.0 * time.delta_seconds();
-
-            // Rotation
-            let angle = normalized_dir.angle_between(Vec3::new(0.0, 0.0, 1.0));
-            *target_rot = Quat::from_rotation_y(if normalized_dir.x > 0.0 {
-                angle
-            } else {
-                -angle
-            });
-            state.state = PlayerStateEnum::MOVING;
+        if distance <= ARRIVAL_DISTANCE {
+            target.current_target = target.waypoints.pop_front();
+            intent.direction = Vec3::ZERO;
+            continue;
+        }
+        intent.direction = if target.waypoints.is_empty() {
+            // Slow down on the last step so the target isn't overshot
+            let max_step = speed.0 * SIMULATION_TIMESTEP;
+            arrive(transform.translation, current_target, speed.0, max_step) / speed.0
         } else {
-            if let Some(current_target) = target.current_target {
-                transform.translation = current_target;
+            // Keeps full speed through the corners of a path
+            seek(transform.translation, current_target, speed.0) / speed.0
+        };
+    }
+}
+
+pub fn character_controller_system(
+    mut query: Query<(
+        &mut Transform,
+        &MovementSpeed,
+        &MovementIntent,
+        &mut CharacterController,
+        Option<&SteeringAgent>,
+        Option<&mut RootMotion>,
+    )>,
+) {
+    for (mut transform, speed, intent, mut controller, steering, root_motion) in query.iter_mut() {
+        // Authored motion replaces the controller, collision still applies afterwards
+        if let Some(mut root_motion) = root_motion {
+            if root_motion.active {
+                controller.desired_velocity = Vec3::ZERO;
+                controller.velocity = Vec3::ZERO;
+                transform.translation += root_motion.take_delta();
+                continue;
             }
-            state.state = PlayerStateEnum::IDLE;
+            root_motion.delta = Vec3::ZERO;
         }
 
-        let angle_to_target = transform.rotation.angle_between(*target_rot);
+        let steering_output = steering.map(|s| s.output).unwrap_or_default();
+        controller.desired_velocity =
+            (intent.direction + steering_output).clamp_length_max(1.0) * speed.0;
+
+        let delta = controller.desired_velocity - controller.velocity;
+        let max_change = controller.acceleration * SIMULATION_TIMESTEP;
+        controller.velocity += delta.clamp_length_max(max_change);
+
+        if controller.velocity.length_squared() > 0.0 {
+            transform.translation += controller.velocity * SIMULATION_TIMESTEP;
+        }
+        if controller.desired_velocity.length_squared() > 0.0 {
+            controller.target_rotation = facing_rotation(controller.desired_velocity);
+        }
+
+        let angle_to_target = transform.rotation.angle_between(controller.target_rotation);
         if angle_to_target > 0.0 {
-            let t = turn_speed / angle_to_target;
-            transform.rotation = transform
-                .rotation
-                .slerp(*target_rot, 1.0_f32.min(t * time.delta_seconds()));
+            let t = controller.turn_rate / angle_to_target;
+            transform.rotation = transform.rotation.slerp(
+                controller.target_rotation,
+                1.0_f32.min(t * SIMULATION_TIMESTEP),
+            );
         }
     }
 }
+
+pub struct MovementPlugin;
+
+impl Plugin for MovementPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(
+            player_input_system
+                .after(input_system)
+                .with_run_criteria(in_game),
+        )
+        .add_system_to_stage(
+            SimulationStage,
+            target_follow_system
+                .label(SimulationSystem::INTENT)
+                .after(SimulationSystem::AI)
+                .before(SimulationSystem::MOVEMENT),
+        )
+        .add_system_to_stage(
+            SimulationStage,
+            character_controller_system
+                .label(SimulationSystem::MOVEMENT)
+                .after(SimulationSystem::PREPARE)
+                .before(SimulationSystem::FINALIZE),
+        );
+    }
+
+    fn name(&self) -> &str {
+        "MovementPlugin"
+    }
+}
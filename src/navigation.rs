@@ -0,0 +1,348 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, VecDeque},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+    collision::StaticCollider,
+    movement::MovementTarget,
+    simulation::{SimulationStage, SimulationSystem},
+};
+
+// Static colliders that paths have to go around, their bounding box is blocked
+#[derive(Component)]
+pub struct NavObstacle;
+
+// Entities that move along paths, set a destination to request a new path
+#[derive(Component, Default)]
+pub struct NavAgent {
+    pub destination: Option<Vec3>,
+}
+
+pub struct NavGridSettings {
+    pub min: Vec2,
+    pub max: Vec2,
+    pub cell_size: f32,
+    // Obstacles are inflated by this so agents don't clip corners
+    pub agent_radius: f32,
+}
+
+impl Default for NavGridSettings {
+    fn default() -> Self {
+        return Self {
+            min: Vec2::new(-10.0, -10.0),
+            max: Vec2::new(10.0, 10.0),
+            cell_size: 0.25,
+            agent_radius: 0.4,
+        };
+    }
+}
+
+// Walkable cells on the xz plane
+pub struct NavGrid {
+    min: Vec2,
+    cell_size: f32,
+    width: i32,
+    height: i32,
+    blocked: Vec<bool>,
+}
+
+impl Default for NavGrid {
+    fn default() -> Self {
+        NavGrid::new(&NavGridSettings::default())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct OpenCell {
+    estimate: f32,
+    index: usize,
+}
+
+impl Eq for OpenCell {}
+
+impl Ord for OpenCell {
+    // Reversed so the BinaryHeap pops the lowest estimate first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .estimate
+            .partial_cmp(&self.estimate)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for OpenCell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl NavGrid {
+    pub fn new(settings: &NavGridSettings) -> NavGrid {
+        let size = (settings.max - settings.min) / settings.cell_size;
+        let width = size.x.ceil().max(1.0) as i32;
+        let height = size.y.ceil().max(1.0) as i32;
+        return NavGrid {
+            min: settings.min,
+            cell_size: settings.cell_size,
+            width,
+            height,
+            blocked: vec![false; (width * height) as usize],
+        };
+    }
+
+    fn index(&self, cell: IVec2) -> usize {
+        (cell.y * self.width + cell.x) as usize
+    }
+
+    fn in_bounds(&self, cell: IVec2) -> bool {
+        cell.x >= 0 && cell.y >= 0 && cell.x < self.width && cell.y < self.height
+    }
+
+    fn cell_at(&self, position: Vec3) -> IVec2 {
+        let local = (Vec2::new(position.x, position.z) - self.min) / self.cell_size;
+        IVec2::new(local.x.floor() as i32, local.y.floor() as i32)
+    }
+
+    fn center(&self, cell: IVec2) -> Vec3 {
+        let p = self.min + (cell.as_vec2() + Vec2::splat(0.5)) * self.cell_size;
+        Vec3::new(p.x, 0.0, p.y)
+    }
+
+    pub fn walkable(&self, position: Vec3) -> bool {
+        self.cell_walkable(self.cell_at(position))
+    }
+
+    fn cell_walkable(&self, cell: IVec2) -> bool {
+        self.in_bounds(cell) && !self.blocked[self.index(cell)]
+    }
+
+    // Marks every cell whose center lies in the (inflated) footprint of the box
+    pub fn block_box(&mut self, transform: &Transform, half_extents: Vec3, inflate: f32) {
+        let extents = half_extents * transform.scale + Vec3::splat(inflate);
+        let radius = Vec2::new(extents.x, extents.z).length();
+        let from = self.cell_at(transform.translation - Vec3::new(radius, 0.0, radius));
+        let to = self.cell_at(transform.translation + Vec3::new(radius, 0.0, radius));
+        let inverse_rotation = transform.rotation.inverse();
+
+        for x in from.x.max(0)..=to.x.min(self.width - 1) {
+            for y in from.y.max(0)..=to.y.min(self.height - 1) {
+                let cell = IVec2::new(x, y);
+                let mut offset = self.center(cell) - transform.translation;
+                offset.y = 0.0;
+                let local = inverse_rotation * offset;
+                if local.x.abs() <= extents.x && local.z.abs() <= extents.z {
+                    let index = self.index(cell);
+                    self.blocked[index] = true;
+                }
+            }
+        }
+    }
+
+    fn nearest_walkable(&self, cell: IVec2) -> Option<IVec2> {
+        let cell = cell.clamp(IVec2::ZERO, IVec2::new(self.width - 1, self.height - 1));
+        for ring in 0..self.width.max(self.height) {
+            for x in -ring..=ring {
+                for y in -ring..=ring {
+                    if x.abs() != ring && y.abs() != ring {
+                        continue;
+                    }
+                    let candidate = cell + IVec2::new(x, y);
+                    if self.cell_walkable(candidate) {
+                        return Some(candidate);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // A* over the 8-connected grid, the result is smoothed and excludes the start
+    pub fn find_path(&self, start: Vec3, goal: Vec3) -> Option<Vec<Vec3>> {
+        let start_cell = self.nearest_walkable(self.cell_at(start))?;
+        let goal_cell = self.nearest_walkable(self.cell_at(goal))?;
+        let start_index = self.index(start_cell);
+        let goal_index = self.index(goal_cell);
+
+        let heuristic = |cell: IVec2| {
+            let d = (cell - goal_cell).abs();
+            let (min, max) = (d.x.min(d.y) as f32, d.x.max(d.y) as f32);
+            (max - min) + min * std::f32::consts::SQRT_2
+        };
+
+        let mut cost = vec![f32::INFINITY; self.blocked.len()];
+        let mut came_from = vec![usize::MAX; self.blocked.len()];
+        let mut open = BinaryHeap::new();
+        cost[start_index] = 0.0;
+        open.push(OpenCell {
+            estimate: heuristic(start_cell),
+            index: start_index,
+        });
+
+        while let Some(OpenCell { index, .. }) = open.pop() {
+            if index == goal_index {
+                break;
+            }
+            let cell = IVec2::new(index as i32 % self.width, index as i32 / self.width);
+            for x in -1..=1 {
+                for y in -1..=1 {
+                    if x == 0 && y == 0 {
+                        continue;
+                    }
+                    let neighbour = cell + IVec2::new(x, y);
+                    if !self.cell_walkable(neighbour) {
+                        continue;
+                    }
+                    // Don't cut corners of blocked cells
+                    if x != 0
+                        && y != 0
+                        && (!self.cell_walkable(cell + IVec2::new(x, 0))
+                            || !self.cell_walkable(cell + IVec2::new(0, y)))
+                    {
+                        continue;
+                    }
+                    let step = if x != 0 && y != 0 {
+                        std::f32::consts::SQRT_2
+                    } else {
+                        1.0
+                    };
+                    let neighbour_index = self.index(neighbour);
+                    let new_cost = cost[index] + step;
+                    if new_cost < cost[neighbour_index] {
+                        cost[neighbour_index] = new_cost;
+                        came_from[neighbour_index] = index;
+                        open.push(OpenCell {
+                            estimate: new_cost + heuristic(neighbour),
+                            index: neighbour_index,
+                        });
+                    }
+                }
+            }
+        }
+
+        if cost[goal_index].is_infinite() {
+            return None;
+        }
+
+        let mut points = VecDeque::new();
+        let mut index = goal_index;
+        while index != start_index {
+            let cell = IVec2::new(index as i32 % self.width, index as i32 / self.width);
+            points.push_front(self.center(cell));
+            index = came_from[index];
+        }
+        // Keep the exact goal if it is reachable, otherwise end in the closest free cell
+        if goal_cell == self.cell_at(goal) {
+            points.pop_back();
+            points.push_back(goal);
+        }
+        points.push_front(start);
+
+        let mut path = self.smooth(&Vec::from(points));
+        path.remove(0);
+        for point in path.iter_mut() {
+            point.y = goal.y;
+        }
+        Some(path)
+    }
+
+    fn line_of_sight(&self, from: Vec3, to: Vec3) -> bool {
+        let distance = Vec2::new(to.x - from.x, to.z - from.z).length();
+        let steps = (distance / (self.cell_size * 0.5)).ceil().max(1.0) as i32;
+        (0..=steps).all(|i| self.walkable(from.lerp(to, i as f32 / steps as f32)))
+    }
+
+    // Skips every point that can be reached in a straight line
+    fn smooth(&self, points: &[Vec3]) -> Vec<Vec3> {
+        let mut path = vec![points[0]];
+        let mut anchor = 0;
+        while anchor < points.len() - 1 {
+            let mut furthest = anchor + 1;
+            for i in (anchor + 2..points.len()).rev() {
+                if self.line_of_sight(points[anchor], points[i]) {
+                    furthest = i;
+                    break;
+                }
+            }
+            path.push(points[furthest]);
+            anchor = furthest;
+        }
+        path
+    }
+}
+
+pub fn nav_grid_build_system(
+    settings: Res<NavGridSettings>,
+    mut grid: ResMut<NavGrid>,
+    obstacles: Query<(&Transform, &StaticCollider), With<NavObstacle>>,
+    changed: Query<
+        (),
+        (
+            With<NavObstacle>,
+            Or<(Added<NavObstacle>, Changed<Transform>)>,
+        ),
+    >,
+    removed: RemovedComponents<NavObstacle>,
+) {
+    if changed.is_empty() && removed.iter().next().is_none() && !settings.is_changed() {
+        return;
+    }
+    let mut new_grid = NavGrid::new(&settings);
+    for (transform, collider) in obstacles.iter() {
+        new_grid.block_box(transform, collider.0.half_extents(), settings.agent_radius);
+    }
+    *grid = new_grid;
+}
+
+pub fn nav_path_system(
+    grid: Res<NavGrid>,
+    mut query: Query<(
+        &Transform,
+        &NavAgent,
+        ChangeTrackers<NavAgent>,
+        &mut MovementTarget,
+    )>,
+) {
+    for (transform, agent, agent_tracker, mut target) in query.iter_mut() {
+        // Replan when the destination changes or obstacles moved
+        if !agent_tracker.is_changed() && !grid.is_changed() {
+            continue;
+        }
+        if let Some(destination) = agent.destination {
+            match grid.find_path(transform.translation, destination) {
+                Some(path) => {
+                    target.waypoints = path.into();
+                    target.current_target = target.waypoints.pop_front();
+                }
+                None => {
+                    target.waypoints.clear();
+                    target.current_target = None;
+                }
+            }
+        }
+    }
+}
+
+pub struct NavigationPlugin;
+
+impl Plugin for NavigationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NavGridSettings>()
+            .init_resource::<NavGrid>()
+            .add_system(nav_grid_build_system)
+            // Plans in the same tick the AI or the player input picked a destination, so the
+            // intent already follows the new path
+            .add_system_to_stage(
+                SimulationStage,
+                nav_path_system
+                    .after(SimulationSystem::AI)
+                    .before(SimulationSystem::INTENT),
+            );
+    }
+
+    fn name(&self) -> &str {
+        "NavigationPlugin"
+    }
+}
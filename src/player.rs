@@ -1,11 +1,22 @@
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::movement::{MovementSpeed, MovementTarget};
+use crate::{
+    animation::{AnimationParameters, Animations},
+    collision::CharacterCollider,
+    combat::{Damage, DamageKind, Health, Hitbox, Hurtbox, Invulnerability, MeleeAttack},
+    movement::{
+        CharacterController, MovementIntent, MovementSpeed, MovementTarget, PlayerControlled,
+    },
+    navigation::NavAgent,
+    root_motion::RootMotion,
+    simulation::SimulatedTransform,
+};
 
 #[derive(Component)]
 pub struct Player;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum PlayerStateEnum {
     IDLE,
     MOVING,
@@ -14,7 +25,6 @@ pub enum PlayerStateEnum {
 #[derive(Component)]
 pub struct PlayerState {
     pub state: PlayerStateEnum,
-    pub animation: Option<usize>,
 }
 
 #[derive(Bundle)]
@@ -24,6 +34,20 @@ pub struct PlayerBundle {
     pub movement_speed: MovementSpeed,
     pub state: PlayerState,
     pub movement_target: MovementTarget,
+    pub movement_intent: MovementIntent,
+    pub controller: CharacterController,
+    pub player_controlled: PlayerControlled,
+    pub nav_agent: NavAgent,
+    pub collider: CharacterCollider,
+    pub health: Health,
+    pub hurtbox: Hurtbox,
+    pub invulnerability: Invulnerability,
+    pub melee_attack: MeleeAttack,
+    pub hitbox: Hitbox,
+    pub simulated_transform: SimulatedTransform,
+    pub animations: Animations,
+    pub animation_parameters: AnimationParameters,
+    pub root_motion: RootMotion,
     #[bundle]
     pub scene_bundle: SceneBundle,
 }
@@ -36,10 +60,85 @@ impl Default for PlayerBundle {
             movement_speed: MovementSpeed(3.0),
             state: PlayerState {
                 state: PlayerStateEnum::IDLE,
-                animation: None,
             },
             movement_target: MovementTarget::default(),
+            movement_intent: MovementIntent::default(),
+            controller: CharacterController::default(),
+            player_controlled: PlayerControlled,
+            nav_agent: NavAgent::default(),
+            collider: CharacterCollider {
+                radius: 0.3,
+                height: 1.8,
+                step_height: 0.3,
+            },
+            health: Health::new(100.0),
+            hurtbox: Hurtbox {
+                radius: 0.3,
+                height: 1.8,
+            },
+            invulnerability: Invulnerability::new(0.5),
+            melee_attack: MeleeAttack::new(0.6, 0.15, 0.35),
+            hitbox: Hitbox::new(
+                Vec3::new(0.0, 0.9, 0.7),
+                0.6,
+                Damage {
+                    amount: 25.0,
+                    kind: DamageKind::PHYSICAL,
+                },
+            ),
+            simulated_transform: SimulatedTransform::default(),
+            animations: Animations::default(),
+            animation_parameters: AnimationParameters::default(),
+            root_motion: RootMotion::default(),
             scene_bundle: SceneBundle::default(),
         };
     }
 }
+
+// Characters moving slower than this count as idle
+pub const MOVING_THRESHOLD: f32 = 0.1;
+
+pub fn player_state_system(mut query: Query<(&CharacterController, &mut PlayerState)>) {
+    for (controller, mut state) in query.iter_mut() {
+        let next = if controller.velocity.length() > MOVING_THRESHOLD {
+            PlayerStateEnum::MOVING
+        } else {
+            PlayerStateEnum::IDLE
+        };
+        if state.state != next {
+            state.state = next;
+        }
+    }
+}
+
+// Exposes the player state to the animation graph
+pub fn player_animation_parameters_system(
+    mut query: Query<(
+        &Transform,
+        &PlayerState,
+        &SimulatedTransform,
+        &MeleeAttack,
+        &mut AnimationParameters,
+    )>,
+) {
+    for (transform, state, simulated, attack, mut parameters) in query.iter_mut() {
+        parameters.set_bool("moving", state.state == PlayerStateEnum::MOVING);
+        set_velocity_parameters(&mut parameters, transform, simulated.velocity());
+        // For graphs with an attack state, the current one has no attack clip
+        parameters.set_bool("attacking", attack.is_attacking());
+    }
+}
+
+// Speed plus the velocity relative to the facing direction, characters face +z. Height
+// changes from the ground snapping don't count
+pub fn set_velocity_parameters(
+    parameters: &mut AnimationParameters,
+    transform: &Transform,
+    velocity: Vec3,
+) {
+    let velocity = Vec3::new(velocity.x, 0.0, velocity.z);
+    let local_velocity = transform.rotation.inverse() * velocity;
+    parameters.set("speed", velocity.length());
+    parameters.set("forward_speed", local_velocity.z);
+    parameters.set("strafe_speed", local_velocity.x);
+}
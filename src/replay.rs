@@ -0,0 +1,382 @@
+use std::fs;
+
+use bevy::{ecs::schedule::ShouldRun, input::InputSystem, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    camera::CameraZoom,
+    input::{InputCommand, InputEvent, MouseFloorPosition, MoveEvent, ZoomEvent},
+    save::{Persistent, PersistentQuery, SaveGame},
+    simulation::SimulationClock,
+};
+
+pub const REPLAY_PATH: &str = "replay.ron";
+const RECORD_KEY: KeyCode = KeyCode::F9;
+const REPLAY_KEY: KeyCode = KeyCode::F10;
+
+// Everything the input plugin produced in a single frame
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct InputFrame {
+    pub delta_seconds: f32,
+    // Fixed simulation ticks that ran in this frame, replayed exactly for determinism
+    #[serde(default)]
+    pub simulation_ticks: u32,
+    pub commands: Vec<InputCommand>,
+    pub movement: Option<Vec2>,
+    pub mouse_floor_positions: Vec<Vec3>,
+    pub zoom: Vec<f32>,
+    pub mouse_buttons: Vec<MouseButton>,
+}
+
+// Frame time of the systems outside the fixed simulation that have to behave the same in a
+// replay, taken from the recorded frame while replaying
+#[derive(Default)]
+pub struct FrameTime {
+    pub delta_seconds: f32,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct InputRecording {
+    // The world when the recording started, restored before the first frame is replayed.
+    // Recordings without it replay from whatever state the world is in
+    #[serde(default)]
+    pub initial_state: Option<SaveGame>,
+    pub frames: Vec<InputFrame>,
+}
+
+impl InputRecording {
+    pub fn load(path: &str) -> Result<InputRecording, String> {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        ron::from_str(&content).map_err(|e| e.to_string())
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let content = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|e| e.to_string())?;
+        fs::write(path, content).map_err(|e| e.to_string())
+    }
+}
+
+pub enum Replay {
+    LIVE,
+    RECORDING(InputRecording),
+    REPLAYING {
+        recording: InputRecording,
+        frame: usize,
+    },
+}
+
+impl Default for Replay {
+    fn default() -> Self {
+        return Replay::LIVE;
+    }
+}
+
+impl Replay {
+    pub fn play(recording: InputRecording) -> Replay {
+        return Replay::REPLAYING {
+            recording,
+            frame: 0,
+        };
+    }
+}
+
+// Run criteria for systems reading the real devices, they pause while a recording is replayed
+pub fn live_input(replay: Option<Res<Replay>>) -> ShouldRun {
+    match replay.as_deref() {
+        Some(Replay::REPLAYING { .. }) => ShouldRun::No,
+        _ => ShouldRun::Yes,
+    }
+}
+
+fn replay_control_system(
+    keys: Res<Input<KeyCode>>,
+    clock: Res<SimulationClock>,
+    zoom: Res<CameraZoom>,
+    query: PersistentQuery,
+    mut replay: ResMut<Replay>,
+) {
+    if keys.just_pressed(RECORD_KEY) {
+        match &*replay {
+            Replay::RECORDING(recording) => {
+                match recording.save(REPLAY_PATH) {
+                    Ok(()) => info!(
+                        "Saved {} recorded frames to {}",
+                        recording.frames.len(),
+                        REPLAY_PATH
+                    ),
+                    Err(e) => error!("Failed to save recording to {}: {}", REPLAY_PATH, e),
+                }
+                *replay = Replay::LIVE;
+            }
+            Replay::LIVE => {
+                info!("Recording input");
+                *replay = Replay::RECORDING(InputRecording {
+                    initial_state: Some(SaveGame::capture(&clock, &zoom, &query)),
+                    frames: Vec::new(),
+                });
+            }
+            Replay::REPLAYING { .. } => (),
+        }
+    }
+
+    if keys.just_pressed(REPLAY_KEY) {
+        match InputRecording::load(REPLAY_PATH) {
+            Ok(recording) => {
+                info!(
+                    "Replaying {} frames from {}",
+                    recording.frames.len(),
+                    REPLAY_PATH
+                );
+                *replay = Replay::play(recording);
+            }
+            Err(e) => error!("Failed to load recording from {}: {}", REPLAY_PATH, e),
+        }
+    }
+}
+
+// Feeds the next recorded frame into the same events and resources the live input writes
+fn replay_input_system(
+    mut commands: Commands,
+    mut replay: ResMut<Replay>,
+    mut clock: ResMut<SimulationClock>,
+    mut zoom: ResMut<CameraZoom>,
+    mut frame_time: ResMut<FrameTime>,
+    query: Query<(Entity, &Persistent)>,
+    mut mouse_buttons: ResMut<Input<MouseButton>>,
+    mut input_event: EventWriter<InputEvent>,
+    mut move_event: EventWriter<MoveEvent>,
+    mut mouse_event: EventWriter<MouseFloorPosition>,
+    mut zoom_event: EventWriter<ZoomEvent>,
+) {
+    let finished = if let Replay::REPLAYING { recording, frame } = &mut *replay {
+        if let (0, Some(state)) = (*frame, &recording.initial_state) {
+            clock.tick = state.simulation_tick;
+            zoom.0 = state.camera_zoom;
+            for (entity, persistent) in query.iter() {
+                if let Some(saved) = state.entities.iter().find(|e| e.object == persistent.0) {
+                    saved.insert_into(&mut commands.entity(entity));
+                }
+            }
+        }
+        if let Some(input) = recording.frames.get(*frame) {
+            for command in input.commands.iter() {
+                input_event.send(InputEvent(*command));
+            }
+            if let Some(movement) = input.movement {
+                move_event.send(MoveEvent(movement));
+            }
+            for position in input.mouse_floor_positions.iter() {
+                mouse_event.send(MouseFloorPosition(*position));
+            }
+            for zoom in input.zoom.iter() {
+                zoom_event.send(ZoomEvent(*zoom));
+            }
+
+            let released: Vec<MouseButton> = mouse_buttons
+                .get_pressed()
+                .filter(|b| !input.mouse_buttons.contains(*b))
+                .copied()
+                .collect();
+            for button in released {
+                mouse_buttons.release(button);
+            }
+            for button in input.mouse_buttons.iter() {
+                mouse_buttons.press(*button);
+            }
+            clock.forced_ticks = Some(input.simulation_ticks);
+            frame_time.delta_seconds = input.delta_seconds;
+            *frame += 1;
+            false
+        } else {
+            // Only now, the last frame still had to run its recorded ticks
+            true
+        }
+    } else {
+        false
+    };
+
+    if finished {
+        info!("Replay finished");
+        clock.forced_ticks = None;
+        *replay = Replay::LIVE;
+    }
+}
+
+// Live frame time, the replay sets it from the recording instead
+fn frame_time_system(time: Res<Time>, replay: Res<Replay>, mut frame_time: ResMut<FrameTime>) {
+    if !matches!(*replay, Replay::REPLAYING { .. }) {
+        frame_time.delta_seconds = time.delta_seconds();
+    }
+}
+
+fn record_input_system(
+    frame_time: Res<FrameTime>,
+    clock: Res<SimulationClock>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    mut replay: ResMut<Replay>,
+    mut input_event: EventReader<InputEvent>,
+    mut move_event: EventReader<MoveEvent>,
+    mut mouse_event: EventReader<MouseFloorPosition>,
+    mut zoom_event: EventReader<ZoomEvent>,
+) {
+    // Always drain the readers so a new recording doesn't start with stale events
+    let frame = InputFrame {
+        delta_seconds: frame_time.delta_seconds,
+        simulation_ticks: clock.ticks_this_frame,
+        commands: input_event.iter().map(|e| e.0).collect(),
+        movement: move_event.iter().last().map(|e| e.0),
+        mouse_floor_positions: mouse_event.iter().map(|e| e.0).collect(),
+        zoom: zoom_event.iter().map(|e| e.0).collect(),
+        mouse_buttons: mouse_buttons.get_pressed().copied().collect(),
+    };
+
+    if let Replay::RECORDING(recording) = &mut *replay {
+        recording.frames.push(frame);
+    }
+}
+
+pub struct ReplayPlugin;
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Replay>()
+            .init_resource::<FrameTime>()
+            .add_system_to_stage(CoreStage::PreUpdate, replay_control_system)
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                replay_input_system
+                    .after(InputSystem)
+                    .after(replay_control_system),
+            )
+            // After the replay, the frame it finishes in is already live
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                frame_time_system.after(replay_input_system),
+            )
+            .add_system_to_stage(CoreStage::PostUpdate, record_input_system);
+    }
+
+    fn name(&self) -> &str {
+        "ReplayPlugin"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        camera::camera_follow_player_system,
+        movement::{
+            CharacterController, MovementIntent, MovementPlugin, MovementSpeed, MovementTarget,
+            PlayerControlled,
+        },
+        navigation::NavAgent,
+        player::Player,
+        save::restore_controller_system,
+        simulation::{SimulatedTransform, SimulationPlugin, SimulationStage, SimulationSystem},
+    };
+
+    // Movement and camera without a window, the input events are only written by the replay
+    fn replay_app() -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(bevy::input::InputPlugin)
+            .add_plugin(SimulationPlugin)
+            .add_plugin(ReplayPlugin)
+            .add_plugin(MovementPlugin)
+            .init_resource::<CameraZoom>()
+            .add_event::<InputEvent>()
+            .add_event::<MoveEvent>()
+            .add_event::<MouseFloorPosition>()
+            .add_event::<ZoomEvent>()
+            .add_system_to_stage(
+                SimulationStage,
+                restore_controller_system
+                    .after(SimulationSystem::PREPARE)
+                    .before(SimulationSystem::AI),
+            )
+            .add_system_to_stage(CoreStage::PostUpdate, camera_follow_player_system);
+        app
+    }
+
+    #[test]
+    fn replay_restores_initial_state_and_drives_movement_and_camera() {
+        let recording: InputRecording =
+            ron::from_str(include_str!("../tests/fixtures/replay_walk_right.ron")).unwrap();
+        let frame_count = recording.frames.len();
+
+        let mut app = replay_app();
+        // Looks along -Z, so the zoom moves it towards -Z
+        let camera = app
+            .world
+            .spawn()
+            .insert(Camera::default())
+            .insert(Transform::default())
+            .insert(GlobalTransform::default())
+            .id();
+        // Away from the recorded start, the initial state has to move it back
+        let player = app
+            .world
+            .spawn()
+            .insert(Player)
+            .insert(Persistent("player".to_string()))
+            .insert(Transform::from_xyz(-3.0, 0.0, -3.0))
+            .insert(SimulatedTransform::default())
+            .insert(MovementSpeed(2.0))
+            .insert(MovementTarget {
+                current_target: None,
+                waypoints: default(),
+            })
+            .insert(MovementIntent::default())
+            .insert(CharacterController::default())
+            .insert(PlayerControlled)
+            .insert(NavAgent::default())
+            .id();
+        let spider = app
+            .world
+            .spawn()
+            .insert(Persistent("spider_0".to_string()))
+            .insert(Transform::from_xyz(9.0, 0.0, 9.0))
+            .id();
+        app.insert_resource(Replay::play(recording));
+
+        for _ in 0..frame_count {
+            app.update();
+        }
+        // Every frame including the last one ran its recorded tick
+        assert_eq!(
+            app.world.resource::<SimulationClock>().tick,
+            frame_count as u64
+        );
+        // Switches back to live input
+        app.update();
+
+        assert!(matches!(app.world.resource::<Replay>(), Replay::LIVE));
+        // Accelerates within 3 ticks, runs 27 at full speed and stops within 3
+        let position = app
+            .world
+            .get::<SimulatedTransform>(player)
+            .and_then(|s| s.current())
+            .unwrap()
+            .translation;
+        assert!(
+            (position - Vec3::new(2.0, 0.0, 2.0)).length() < 1e-3,
+            "player ended at {}",
+            position
+        );
+        assert_eq!(
+            app.world.get::<Transform>(spider).unwrap().translation,
+            Vec3::new(4.0, 0.0, 4.0)
+        );
+        // The recorded zoom plus one scroll of 2 lines
+        assert!((app.world.resource::<CameraZoom>().0 - 3.3).abs() < 1e-5);
+        // Followed the player with the recorded frame times and caught up while it stood still
+        let camera_position = app.world.get::<Transform>(camera).unwrap().translation;
+        assert!(
+            (camera_position - Vec3::new(2.0, 0.0, -1.3)).length() < 1e-3,
+            "camera ended at {}",
+            camera_position
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use bevy::{
+    animation::{animation_player, Keyframes, VariableCurve},
+    gltf::Gltf,
+    prelude::*,
+    transform::TransformSystem,
+};
+
+use crate::{
+    animation::{AnimationGraph, AnimationGraphState, AnimationPlayerLink, Animations},
+    app_state::in_game,
+    scene_ready::NamedChildren,
+};
+
+// Horizontal root bone motion of the playing clip, consumed by the character controller
+#[derive(Component, Default)]
+pub struct RootMotion {
+    // The current animation state uses root motion, the controller ignores the intent
+    pub active: bool,
+    // World space motion since the controller last moved the character
+    pub delta: Vec3,
+    previous_clip: String,
+    previous_time: Option<f32>,
+}
+
+impl RootMotion {
+    pub fn take_delta(&mut self) -> Vec3 {
+        std::mem::take(&mut self.delta)
+    }
+}
+
+fn sample_translation(curve: &VariableCurve, time: f32) -> Option<Vec3> {
+    let translations = match &curve.keyframes {
+        Keyframes::Translation(translations) => translations,
+        _ => return None,
+    };
+    let timestamps = &curve.keyframe_timestamps;
+    let next = timestamps
+        .iter()
+        .position(|t| *t > time)
+        .unwrap_or(timestamps.len());
+    if next == 0 {
+        return translations.first().copied();
+    }
+    if next == timestamps.len() {
+        return translations.last().copied();
+    }
+    let (start, end) = (timestamps[next - 1], timestamps[next]);
+    let lerp = (time - start) / (end - start);
+    Some(translations[next - 1].lerp(translations[next], lerp))
+}
+
+// Motion of the curve between two elapsed times of the player, counting full loops
+fn translation_delta(
+    curve: &VariableCurve,
+    from: f32,
+    to: f32,
+    duration: f32,
+    looping: bool,
+) -> Vec3 {
+    let sample = |time: f32| sample_translation(curve, time).unwrap_or_default();
+    if !looping || duration <= 0.0 {
+        return sample(to.min(duration)) - sample(from.min(duration));
+    }
+    let loops = (to / duration).floor() - (from / duration).floor();
+    let per_loop = sample(duration) - sample(0.0);
+    sample(to % duration) - sample(from % duration) + per_loop * loops
+}
+
+// Runs right after the animation player sampled the clips, moves the horizontal motion of
+// the root bone over to RootMotion and keeps the bone in place
+pub fn root_motion_system(
+    graphs: Res<Assets<AnimationGraph>>,
+    gltfs: Res<Assets<Gltf>>,
+    clips: Res<Assets<AnimationClip>>,
+    mut query: Query<(
+        &Animations,
+        &AnimationPlayerLink,
+        &AnimationGraphState,
+        &NamedChildren,
+        &mut RootMotion,
+    )>,
+    players: Query<&AnimationPlayer>,
+    parent_query: Query<&Parent>,
+    global_transforms: Query<&GlobalTransform>,
+    mut transforms: Query<&mut Transform>,
+) {
+    for (animations, link, state, named_children, mut root_motion) in query.iter_mut() {
+        let (graph, gltf) = match (graphs.get(&animations.graph), gltfs.get(&animations.gltf)) {
+            (Some(graph), Some(gltf)) => (graph, gltf),
+            _ => continue,
+        };
+        let node = match graph.states.get(&state.current) {
+            Some(node) if node.root_motion => node,
+            _ => {
+                root_motion.active = false;
+                root_motion.previous_time = None;
+                continue;
+            }
+        };
+        let (player, clip) = match (
+            players.get(link.0),
+            gltf.named_animations
+                .get(&state.clip)
+                .and_then(|clip| clips.get(clip)),
+        ) {
+            (Ok(player), Some(clip)) => (player, clip),
+            _ => continue,
+        };
+        // Curve paths start below the AnimationPlayer entity
+        let curve = clip
+            .curves()
+            .iter()
+            .find_map(|(path, curves)| match path.parts.last() {
+                Some(name) if name.as_str() == graph.root_bone => curves
+                    .iter()
+                    .find(|c| matches!(c.keyframes, Keyframes::Translation(_))),
+                _ => None,
+            });
+        let curve = match curve {
+            Some(curve) => curve,
+            None => {
+                warn!(
+                    "Clip {} has no translation for bone {}",
+                    state.clip, graph.root_bone
+                );
+                continue;
+            }
+        };
+        let bone = match named_children.get(&graph.root_bone) {
+            Some(bone) => bone,
+            None => continue,
+        };
+        // Bone space to world space, last frame's transform is close enough
+        let to_world = parent_query
+            .get(bone)
+            .and_then(|parent| global_transforms.get(parent.get()))
+            .map(|global| global.compute_matrix())
+            .unwrap_or(Mat4::IDENTITY);
+
+        if state.clip != root_motion.previous_clip {
+            root_motion.previous_clip = state.clip.clone();
+            root_motion.previous_time = None;
+        }
+        let time = player.elapsed();
+        let from = root_motion.previous_time.unwrap_or(0.0);
+        let duration = clip.duration();
+        let mut delta = to_world.transform_vector3(translation_delta(
+            curve,
+            from,
+            time,
+            duration,
+            node.looping,
+        ));
+        delta.y = 0.0;
+        root_motion.active = true;
+        root_motion.delta += delta;
+        root_motion.previous_time = Some(time);
+
+        // Only the vertical offset of the bone stays, the rest moved to the character
+        let looped_time = if node.looping && duration > 0.0 {
+            time % duration
+        } else {
+            time.min(duration)
+        };
+        let rest = sample_translation(curve, 0.0).unwrap_or_default();
+        let offset = to_world
+            .transform_vector3(sample_translation(curve, looped_time).unwrap_or_default() - rest);
+        if let Ok(mut transform) = transforms.get_mut(bone) {
+            transform.translation = rest
+                + to_world
+                    .inverse()
+                    .transform_vector3(Vec3::new(0.0, offset.y, 0.0));
+        }
+    }
+}
+
+pub struct RootMotionPlugin;
+
+impl Plugin for RootMotionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            root_motion_system
+                .after(animation_player)
+                .before(TransformSystem::TransformPropagate)
+                .with_run_criteria(in_game),
+        );
+    }
+
+    fn name(&self) -> &str {
+        "RootMotionPlugin"
+    }
+}
@@ -0,0 +1,599 @@
+use std::fs;
+
+use bevy::{ecs::system::EntityCommands, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ai::{AiController, AiStateEnum},
+    app_state::in_game,
+    camera::CameraZoom,
+    combat::Health,
+    level::{Level, LevelSpawner, LEVEL_PATH},
+    movement::{CharacterController, MovementTarget},
+    navigation::NavAgent,
+    player::{PlayerState, PlayerStateEnum},
+    simulation::{SimulatedTransform, SimulationClock, SimulationStage, SimulationSystem},
+    test_scene::NormalVisualizerMaterial,
+};
+
+pub const SAVE_PATH: &str = "savegame.ron";
+const SAVE_KEY: KeyCode = KeyCode::F5;
+const LOAD_KEY: KeyCode = KeyCode::F8;
+
+// Bump when the format changes, keep the previous schema as its own struct and convert it
+// in SaveGame::parse
+pub const SAVE_VERSION: u32 = 1;
+
+// Where F5 and F8 save and load
+pub struct SavePath(pub String);
+
+impl Default for SavePath {
+    fn default() -> Self {
+        return Self(SAVE_PATH.to_string());
+    }
+}
+
+// Entities that are written to save games, respawned from the level object of that name
+#[derive(Component, Clone, Debug)]
+pub struct Persistent(pub String);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedTransform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedMovementTarget {
+    pub current_target: Option<Vec3>,
+    pub waypoints: Vec<Vec3>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedAi {
+    pub profile: String,
+    pub state: AiStateEnum,
+    pub home: Option<Vec3>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedEntity {
+    // Name of the level object the entity was spawned from
+    pub object: String,
+    pub transform: SavedTransform,
+    pub velocity: Option<Vec3>,
+    pub health: Option<Health>,
+    pub movement_target: Option<SavedMovementTarget>,
+    pub nav_destination: Option<Vec3>,
+    pub player_state: Option<PlayerStateEnum>,
+    pub ai: Option<SavedAi>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SaveGame {
+    pub version: u32,
+    pub simulation_tick: u64,
+    pub camera_zoom: f32,
+    pub entities: Vec<SavedEntity>,
+}
+
+// Applied to the CharacterController of the spawned bundle before the next tick
+#[derive(Component)]
+pub struct RestoredController {
+    velocity: Vec3,
+    rotation: Quat,
+}
+
+// Read first to decide how to parse the rest of the file
+#[derive(Deserialize)]
+struct SaveHeader {
+    // Saves from before versioning have none
+    #[serde(default)]
+    version: u32,
+}
+
+// Version 0, written before saves were versioned and kept the camera zoom
+#[derive(Deserialize)]
+struct SaveGameV0 {
+    simulation_tick: u64,
+    entities: Vec<SavedEntity>,
+}
+
+impl From<SaveGameV0> for SaveGame {
+    fn from(save: SaveGameV0) -> Self {
+        return SaveGame {
+            version: SAVE_VERSION,
+            simulation_tick: save.simulation_tick,
+            camera_zoom: 0.0,
+            entities: save.entities,
+        };
+    }
+}
+
+// Everything a save game needs to know about the persistent entities
+pub type PersistentQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static Persistent,
+        &'static Transform,
+        Option<&'static SimulatedTransform>,
+        Option<&'static CharacterController>,
+        Option<&'static Health>,
+        Option<&'static MovementTarget>,
+        Option<&'static NavAgent>,
+        Option<&'static PlayerState>,
+        Option<&'static AiController>,
+    ),
+>;
+
+impl SavedEntity {
+    // Replaces the saved components of the entity, the velocity is applied before the next
+    // tick. A new SimulatedTransform makes sure nothing interpolates from the old position
+    pub fn insert_into(&self, entity_commands: &mut EntityCommands) {
+        entity_commands
+            .insert(Transform {
+                translation: self.transform.translation,
+                rotation: self.transform.rotation,
+                scale: self.transform.scale,
+            })
+            .insert(SimulatedTransform::default());
+        if let Some(health) = self.health {
+            entity_commands.insert(health);
+        }
+        if let Some(target) = &self.movement_target {
+            entity_commands.insert(MovementTarget {
+                current_target: target.current_target,
+                waypoints: target.waypoints.iter().copied().collect(),
+            });
+        }
+        if let Some(destination) = self.nav_destination {
+            entity_commands.insert(NavAgent {
+                destination: Some(destination),
+            });
+        }
+        if let Some(state) = self.player_state {
+            entity_commands.insert(PlayerState { state });
+        }
+        if let Some(ai) = &self.ai {
+            let mut controller = AiController::new(&ai.profile);
+            controller.state = ai.state;
+            controller.home = ai.home;
+            entity_commands.insert(controller);
+        }
+        if let Some(velocity) = self.velocity {
+            entity_commands.insert(RestoredController {
+                velocity,
+                rotation: self.transform.rotation,
+            });
+        }
+    }
+}
+
+impl SaveGame {
+    // Snapshot of all persistent entities
+    pub fn capture(
+        clock: &SimulationClock,
+        zoom: &CameraZoom,
+        query: &PersistentQuery,
+    ) -> SaveGame {
+        let entities = query
+            .iter()
+            .map(
+                |(
+                    persistent,
+                    transform,
+                    simulated,
+                    controller,
+                    health,
+                    target,
+                    agent,
+                    state,
+                    ai,
+                )| {
+                    let transform = simulated.and_then(|s| s.current()).unwrap_or(transform);
+                    SavedEntity {
+                        object: persistent.0.clone(),
+                        transform: SavedTransform {
+                            translation: transform.translation,
+                            rotation: transform.rotation,
+                            scale: transform.scale,
+                        },
+                        velocity: controller.map(|c| c.velocity),
+                        health: health.copied(),
+                        movement_target: target.map(|t| SavedMovementTarget {
+                            current_target: t.current_target,
+                            waypoints: t.waypoints.iter().copied().collect(),
+                        }),
+                        nav_destination: agent.and_then(|a| a.destination),
+                        player_state: state.map(|s| s.state),
+                        ai: ai.map(|ai| SavedAi {
+                            profile: ai.profile.clone(),
+                            state: ai.state,
+                            home: ai.home,
+                        }),
+                    }
+                },
+            )
+            .collect();
+        return SaveGame {
+            version: SAVE_VERSION,
+            simulation_tick: clock.tick,
+            camera_zoom: zoom.0,
+            entities,
+        };
+    }
+
+    pub fn to_ron(&self) -> Result<String, String> {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|e| e.to_string())
+    }
+
+    // Parses a save game of any supported version into the current one
+    pub fn parse(content: &str) -> Result<SaveGame, String> {
+        let header: SaveHeader = ron::from_str(content).map_err(|e| e.to_string())?;
+        match header.version {
+            0 => ron::from_str::<SaveGameV0>(content)
+                .map(SaveGame::from)
+                .map_err(|e| e.to_string()),
+            SAVE_VERSION => ron::from_str(content).map_err(|e| e.to_string()),
+            version => Err(format!(
+                "Unsupported save version {}, the current version is {}",
+                version, SAVE_VERSION
+            )),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<SaveGame, String> {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        SaveGame::parse(&content)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        fs::write(path, self.to_ron()?).map_err(|e| e.to_string())
+    }
+}
+
+fn save_system(
+    keys: Res<Input<KeyCode>>,
+    path: Res<SavePath>,
+    clock: Res<SimulationClock>,
+    zoom: Res<CameraZoom>,
+    query: PersistentQuery,
+) {
+    if !keys.just_pressed(SAVE_KEY) {
+        return;
+    }
+
+    let save_game = SaveGame::capture(&clock, &zoom, &query);
+    match save_game.save(&path.0) {
+        Ok(()) => info!("Saved the game to {}", path.0),
+        Err(e) => error!("Failed to save the game to {}: {}", path.0, e),
+    }
+}
+
+// Replaces all persistent entities with the ones from the save game
+fn load_system(
+    mut commands: Commands,
+    keys: Res<Input<KeyCode>>,
+    path: Res<SavePath>,
+    asset_server: Res<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut custom_materials: ResMut<Assets<NormalVisualizerMaterial>>,
+    mut clock: ResMut<SimulationClock>,
+    mut zoom: ResMut<CameraZoom>,
+    persistent: Query<Entity, With<Persistent>>,
+) {
+    if !keys.just_pressed(LOAD_KEY) {
+        return;
+    }
+
+    let (save_game, level) = match (SaveGame::load(&path.0), Level::load(LEVEL_PATH)) {
+        (Ok(save_game), Ok(level)) => (save_game, level),
+        (Err(e), _) => {
+            error!("Failed to load the game from {}: {}", path.0, e);
+            return;
+        }
+        (_, Err(e)) => {
+            error!("Failed to load level from {}: {}", LEVEL_PATH, e);
+            return;
+        }
+    };
+
+    for entity in persistent.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    clock.tick = save_game.simulation_tick;
+    zoom.0 = save_game.camera_zoom;
+
+    let mut spawner = LevelSpawner {
+        commands,
+        asset_server: &asset_server,
+        meshes: &mut meshes,
+        materials: &mut materials,
+        custom_materials: &mut custom_materials,
+        tileable_textures: Vec::new(),
+    };
+    for saved in save_game.entities.iter() {
+        let object = match level.object(&saved.object) {
+            Some(object) => object,
+            None => {
+                warn!("Saved object {} is not part of the level", saved.object);
+                continue;
+            }
+        };
+        let entity = spawner.spawn(object, None);
+
+        saved.insert_into(&mut spawner.commands.entity(entity));
+    }
+    info!("Loaded the game from {}", path.0);
+}
+
+pub fn restore_controller_system(
+    mut commands: Commands,
+    mut query: Query<(Entity, &RestoredController, &mut CharacterController)>,
+) {
+    for (entity, restored, mut controller) in query.iter_mut() {
+        controller.velocity = restored.velocity;
+        // Otherwise the character turns back to the spawn rotation
+        controller.target_rotation = restored.rotation;
+        commands.entity(entity).remove::<RestoredController>();
+    }
+}
+
+pub struct SavePlugin;
+
+impl Plugin for SavePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SavePath>()
+            .add_system(save_system.with_run_criteria(in_game))
+            .add_system(load_system.with_run_criteria(in_game))
+            .add_system_to_stage(
+                SimulationStage,
+                restore_controller_system
+                    .after(SimulationSystem::PREPARE)
+                    .before(SimulationSystem::AI),
+            );
+    }
+
+    fn name(&self) -> &str {
+        "SavePlugin"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{player::Player, simulation::SimulationPlugin};
+
+    fn save_game() -> SaveGame {
+        return SaveGame {
+            version: SAVE_VERSION,
+            simulation_tick: 1234,
+            camera_zoom: -2.5,
+            entities: vec![
+                SavedEntity {
+                    object: "player".to_string(),
+                    transform: SavedTransform {
+                        translation: Vec3::new(1.0, 0.0, -2.0),
+                        rotation: Quat::from_rotation_y(0.5),
+                        scale: Vec3::ONE,
+                    },
+                    velocity: Some(Vec3::new(0.5, 0.0, 0.0)),
+                    health: Some(Health {
+                        current: 40.0,
+                        max: 100.0,
+                    }),
+                    movement_target: Some(SavedMovementTarget {
+                        current_target: Some(Vec3::new(3.0, 0.0, 3.0)),
+                        waypoints: vec![Vec3::new(4.0, 0.0, 5.0)],
+                    }),
+                    nav_destination: Some(Vec3::new(4.0, 0.0, 5.0)),
+                    player_state: Some(PlayerStateEnum::MOVING),
+                    ai: None,
+                },
+                SavedEntity {
+                    object: "spider_0".to_string(),
+                    transform: SavedTransform {
+                        translation: Vec3::new(-4.0, 0.0, 6.0),
+                        rotation: Quat::IDENTITY,
+                        scale: Vec3::ONE,
+                    },
+                    velocity: None,
+                    health: None,
+                    movement_target: None,
+                    nav_destination: None,
+                    player_state: None,
+                    ai: Some(SavedAi {
+                        profile: "spider".to_string(),
+                        state: AiStateEnum::PATROL,
+                        home: Some(Vec3::new(-4.0, 0.0, 6.0)),
+                    }),
+                },
+            ],
+        };
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = std::env::temp_dir().join("project_silva_save_round_trip.ron");
+        let path = path.to_str().unwrap();
+        let saved = save_game();
+        saved.save(path).unwrap();
+        let loaded = SaveGame::load(path);
+        let _ = fs::remove_file(path);
+        assert_eq!(loaded.unwrap(), saved);
+    }
+
+    #[test]
+    fn version_0_is_migrated() {
+        let mut current = save_game();
+        current.camera_zoom = 0.0;
+        // The current format without the fields version 0 didn't have
+        let v0: String = current
+            .to_ron()
+            .unwrap()
+            .lines()
+            .filter(|line| {
+                let line = line.trim_start();
+                !line.starts_with("version:") && !line.starts_with("camera_zoom:")
+            })
+            .collect::<Vec<&str>>()
+            .join("\n");
+        assert_eq!(SaveGame::parse(&v0).unwrap(), current);
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let mut newer = save_game();
+        newer.version = SAVE_VERSION + 1;
+        assert!(SaveGame::parse(&newer.to_ron().unwrap()).is_err());
+    }
+
+    // Saving and loading without a window, the level objects are respawned from LEVEL_PATH
+    fn save_app(path: &str) -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(bevy::asset::AssetPlugin)
+            .add_plugin(SimulationPlugin)
+            .add_plugin(SavePlugin)
+            .add_asset::<Mesh>()
+            .add_asset::<StandardMaterial>()
+            .add_asset::<NormalVisualizerMaterial>()
+            .init_resource::<Input<KeyCode>>()
+            .init_resource::<CameraZoom>()
+            .insert_resource(SavePath(path.to_string()));
+        // Nothing ticks until the test forces it
+        app.world.resource_mut::<SimulationClock>().paused = true;
+        app
+    }
+
+    // Nothing clears the keys without the input plugin
+    fn press(app: &mut App, key: KeyCode) {
+        app.world.resource_mut::<Input<KeyCode>>().press(key);
+        app.update();
+        let mut keys = app.world.resource_mut::<Input<KeyCode>>();
+        keys.release(key);
+        keys.clear();
+    }
+
+    fn persistent(app: &mut App, name: &str) -> Entity {
+        let mut query = app.world.query::<(Entity, &Persistent)>();
+        let found: Vec<Entity> = query
+            .iter(&app.world)
+            .filter(|(_, persistent)| persistent.0 == name)
+            .map(|(entity, _)| entity)
+            .collect();
+        assert_eq!(found.len(), 1, "expected one {}", name);
+        found[0]
+    }
+
+    #[test]
+    fn load_restores_saved_entities() {
+        let path = std::env::temp_dir().join("project_silva_save_load_app.ron");
+        let mut app = save_app(path.to_str().unwrap());
+        let rotation = Quat::from_rotation_y(0.5);
+        // Names of level objects
+        let player = app
+            .world
+            .spawn()
+            .insert(Persistent("Player_1".to_string()))
+            .insert(Transform::from_xyz(1.0, 0.0, 2.0).with_rotation(rotation))
+            .insert(SimulatedTransform::default())
+            .insert(CharacterController {
+                velocity: Vec3::new(0.5, 0.0, 0.0),
+                ..default()
+            })
+            .insert(Health {
+                current: 40.0,
+                max: 100.0,
+            })
+            .insert(MovementTarget {
+                current_target: Some(Vec3::new(3.0, 0.0, 3.0)),
+                waypoints: [Vec3::new(4.0, 0.0, 5.0)].into(),
+            })
+            .insert(NavAgent {
+                destination: Some(Vec3::new(4.0, 0.0, 5.0)),
+            })
+            .insert(PlayerState {
+                state: PlayerStateEnum::MOVING,
+            })
+            .id();
+        let mut ai = AiController::new("spider");
+        ai.state = AiStateEnum::PATROL;
+        ai.home = Some(Vec3::new(-4.0, 0.0, 6.0));
+        let enemy = app
+            .world
+            .spawn()
+            .insert(Persistent("enemy_0".to_string()))
+            .insert(Transform::from_xyz(-4.0, 0.0, 6.0))
+            .insert(ai)
+            .id();
+        app.world.resource_mut::<SimulationClock>().tick = 42;
+        app.world.resource_mut::<CameraZoom>().0 = 1.5;
+
+        press(&mut app, SAVE_KEY);
+
+        app.world.get_mut::<Transform>(player).unwrap().translation = Vec3::new(9.0, 0.0, 9.0);
+        app.world.get_mut::<Health>(player).unwrap().current = 1.0;
+        app.world.despawn(enemy);
+        app.world.resource_mut::<SimulationClock>().tick = 100;
+        app.world.resource_mut::<CameraZoom>().0 = 0.0;
+
+        press(&mut app, LOAD_KEY);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(app.world.resource::<SimulationClock>().tick, 42);
+        assert_eq!(app.world.resource::<CameraZoom>().0, 1.5);
+
+        // Respawned from the level, the old entity is gone
+        let player = persistent(&mut app, "Player_1");
+        assert!(app.world.get::<Player>(player).is_some());
+        assert_eq!(
+            app.world.get::<Transform>(player).unwrap().translation,
+            Vec3::new(1.0, 0.0, 2.0)
+        );
+        assert_eq!(
+            *app.world.get::<Health>(player).unwrap(),
+            Health {
+                current: 40.0,
+                max: 100.0,
+            }
+        );
+        let target = app.world.get::<MovementTarget>(player).unwrap();
+        assert_eq!(target.current_target, Some(Vec3::new(3.0, 0.0, 3.0)));
+        assert_eq!(
+            target.waypoints.iter().copied().collect::<Vec<Vec3>>(),
+            vec![Vec3::new(4.0, 0.0, 5.0)]
+        );
+        assert_eq!(
+            app.world.get::<NavAgent>(player).unwrap().destination,
+            Some(Vec3::new(4.0, 0.0, 5.0))
+        );
+        assert_eq!(
+            app.world.get::<PlayerState>(player).unwrap().state,
+            PlayerStateEnum::MOVING
+        );
+
+        let enemy = persistent(&mut app, "enemy_0");
+        let ai = app.world.get::<AiController>(enemy).unwrap();
+        assert_eq!(ai.profile, "spider");
+        assert_eq!(ai.state, AiStateEnum::PATROL);
+        assert_eq!(ai.home, Some(Vec3::new(-4.0, 0.0, 6.0)));
+
+        // The controller of the spawned bundle gets the saved velocity on the next tick
+        assert!(app.world.get::<RestoredController>(player).is_some());
+        {
+            let mut clock = app.world.resource_mut::<SimulationClock>();
+            clock.paused = false;
+            clock.forced_ticks = Some(1);
+        }
+        app.update();
+        assert!(app.world.get::<RestoredController>(player).is_none());
+        let controller = app.world.get::<CharacterController>(player).unwrap();
+        assert_eq!(controller.velocity, Vec3::new(0.5, 0.0, 0.0));
+        assert_eq!(controller.target_rotation, rotation);
+    }
+}
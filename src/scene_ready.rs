@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+
+use bevy::{prelude::*, scene::SceneInstance};
+
+// Runs after the scene spawner in PreUpdate so the ready scenes are visible to all of Update
+#[derive(StageLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SceneReadyStage;
+
+// The scene of a SceneBundle finished spawning its entities
+#[derive(Component)]
+pub struct SceneReady;
+
+// Entities of the spawned scene by their glTF node name, e.g. bones, meshes and sockets
+#[derive(Component, Default)]
+pub struct NamedChildren(pub HashMap<String, Entity>);
+
+impl NamedChildren {
+    pub fn get(&self, name: &str) -> Option<Entity> {
+        self.0.get(name).copied()
+    }
+}
+
+// Sent once per scene instance, SceneReady and NamedChildren are already on the root
+#[derive(Clone, Copy, Debug)]
+pub struct SceneReadyEvent {
+    pub root: Entity,
+}
+
+fn scene_ready_system(
+    mut commands: Commands,
+    scene_spawner: Res<SceneSpawner>,
+    scenes: Query<(Entity, &SceneInstance), Without<SceneReady>>,
+    names: Query<&Name>,
+    mut ready_events: EventWriter<SceneReadyEvent>,
+) {
+    for (root, instance) in scenes.iter() {
+        if !scene_spawner.instance_is_ready(**instance) {
+            continue;
+        }
+        let mut named_children = NamedChildren::default();
+        if let Some(entities) = scene_spawner.iter_instance_entities(**instance) {
+            for entity in entities {
+                if let Ok(name) = names.get(entity) {
+                    named_children
+                        .0
+                        .entry(name.as_str().to_string())
+                        .or_insert(entity);
+                }
+            }
+        }
+        commands
+            .entity(root)
+            .insert(SceneReady)
+            .insert(named_children);
+        ready_events.send(SceneReadyEvent { root });
+    }
+}
+
+pub struct SceneReadyPlugin;
+
+impl Plugin for SceneReadyPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SceneReadyEvent>()
+            .add_stage_after(
+                CoreStage::PreUpdate,
+                SceneReadyStage,
+                SystemStage::parallel(),
+            )
+            .add_system_to_stage(SceneReadyStage, scene_ready_system);
+    }
+
+    fn name(&self) -> &str {
+        "SceneReadyPlugin"
+    }
+}
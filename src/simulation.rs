@@ -0,0 +1,184 @@
+use bevy::{ecs::schedule::ShouldRun, prelude::*, transform::TransformSystem};
+
+pub const SIMULATION_TIMESTEP: f32 = 1.0 / 60.0;
+// Drop the remaining time after a long hitch instead of trying to catch up forever
+const MAX_TICKS_PER_FRAME: u32 = 8;
+
+// Runs after Update with a fixed delta of SIMULATION_TIMESTEP, zero or more times per frame
+#[derive(StageLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SimulationStage;
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SimulationSystem {
+    PREPARE,
+    AI,
+    INTENT,
+    STEERING,
+    MOVEMENT,
+    COLLISION,
+    GROUND,
+    COMBAT,
+    FINALIZE,
+    INTERPOLATE,
+}
+
+pub struct SimulationClock {
+    pub tick: u64,
+    pub ticks_this_frame: u32,
+    // Replays run the recorded amount of ticks per frame instead of accumulating real time
+    pub forced_ticks: Option<u32>,
+    // No ticks run and no time accumulates, e.g. in menus
+    pub paused: bool,
+    accumulator: f32,
+}
+
+impl Default for SimulationClock {
+    fn default() -> Self {
+        return Self {
+            tick: 0,
+            ticks_this_frame: 0,
+            forced_ticks: None,
+            paused: false,
+            accumulator: 0.0,
+        };
+    }
+}
+
+impl SimulationClock {
+    // How far the frame is between the last and the next tick
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / SIMULATION_TIMESTEP).clamp(0.0, 1.0)
+    }
+}
+
+// Entities whose Transform is driven by the simulation, rendering interpolates between ticks
+#[derive(Component, Default)]
+pub struct SimulatedTransform {
+    previous: Option<Transform>,
+    current: Option<Transform>,
+}
+
+impl SimulatedTransform {
+    // Use after moving the entity outside of the simulation, e.g. a teleport
+    pub fn reset(&mut self) {
+        self.previous = None;
+        self.current = None;
+    }
+
+    // The transform of the last tick, the Transform itself is interpolated outside the simulation
+    pub fn current(&self) -> Option<&Transform> {
+        self.current.as_ref()
+    }
+
+    // How far the last tick actually moved the entity, including collision and root motion
+    pub fn velocity(&self) -> Vec3 {
+        match (self.previous, self.current) {
+            (Some(previous), Some(current)) => {
+                (current.translation - previous.translation) / SIMULATION_TIMESTEP
+            }
+            _ => Vec3::ZERO,
+        }
+    }
+}
+
+fn simulation_run_criteria(
+    time: Res<Time>,
+    mut clock: ResMut<SimulationClock>,
+    mut looping: Local<bool>,
+) -> ShouldRun {
+    if clock.paused {
+        clock.ticks_this_frame = 0;
+        return ShouldRun::No;
+    }
+
+    if !*looping {
+        clock.ticks_this_frame = 0;
+        if clock.forced_ticks.is_none() {
+            clock.accumulator += time.delta_seconds();
+        }
+    }
+
+    let run = match clock.forced_ticks {
+        Some(ticks) => clock.ticks_this_frame < ticks,
+        None => {
+            clock.accumulator >= SIMULATION_TIMESTEP && clock.ticks_this_frame < MAX_TICKS_PER_FRAME
+        }
+    };
+
+    if run {
+        if clock.forced_ticks.is_none() {
+            clock.accumulator -= SIMULATION_TIMESTEP;
+        }
+        clock.tick += 1;
+        clock.ticks_this_frame += 1;
+        *looping = true;
+        ShouldRun::YesAndCheckAgain
+    } else {
+        clock.accumulator = clock.accumulator.min(SIMULATION_TIMESTEP);
+        *looping = false;
+        ShouldRun::No
+    }
+}
+
+// Restores the simulated state that the interpolation overwrote last frame
+fn prepare_tick_system(mut query: Query<(&mut Transform, &mut SimulatedTransform)>) {
+    for (mut transform, mut simulated) in query.iter_mut() {
+        if let Some(current) = simulated.current {
+            *transform = current;
+        }
+        simulated.previous = Some(*transform);
+    }
+}
+
+fn finalize_tick_system(mut query: Query<(&Transform, &mut SimulatedTransform)>) {
+    for (transform, mut simulated) in query.iter_mut() {
+        simulated.current = Some(*transform);
+    }
+}
+
+fn interpolate_transform_system(
+    clock: Res<SimulationClock>,
+    mut query: Query<(&mut Transform, &SimulatedTransform)>,
+) {
+    let alpha = clock.alpha();
+    for (mut transform, simulated) in query.iter_mut() {
+        if let (Some(previous), Some(current)) = (simulated.previous, simulated.current) {
+            transform.translation = previous.translation.lerp(current.translation, alpha);
+            transform.rotation = previous.rotation.slerp(current.rotation, alpha);
+            transform.scale = previous.scale.lerp(current.scale, alpha);
+        }
+    }
+}
+
+pub struct SimulationPlugin;
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SimulationClock>()
+            .add_stage_after(
+                CoreStage::Update,
+                SimulationStage,
+                SystemStage::parallel().with_run_criteria(simulation_run_criteria),
+            )
+            .add_system_to_stage(
+                SimulationStage,
+                prepare_tick_system.label(SimulationSystem::PREPARE),
+            )
+            .add_system_to_stage(
+                SimulationStage,
+                finalize_tick_system
+                    .label(SimulationSystem::FINALIZE)
+                    .after(SimulationSystem::PREPARE),
+            )
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                interpolate_transform_system
+                    .label(SimulationSystem::INTERPOLATE)
+                    .before(TransformSystem::TransformPropagate),
+            );
+    }
+
+    fn name(&self) -> &str {
+        "SimulationPlugin"
+    }
+}
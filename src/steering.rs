@@ -0,0 +1,254 @@
+use bevy::prelude::*;
+
+use crate::{
+    collision::CharacterCollider,
+    movement::{CharacterController, MovementIntent, MovementSpeed, MovementTarget},
+    simulation::{SimulationStage, SimulationSystem},
+};
+
+// Desired velocity towards the target at full speed
+pub fn seek(position: Vec3, target: Vec3, max_speed: f32) -> Vec3 {
+    planar(target - position).normalize_or_zero() * max_speed
+}
+
+// Like seek, but slows down linearly inside the slowing radius to stop on the target
+pub fn arrive(position: Vec3, target: Vec3, max_speed: f32, slowing_radius: f32) -> Vec3 {
+    let offset = planar(target - position);
+    let distance = offset.length();
+    if distance <= 0.0 {
+        return Vec3::ZERO;
+    }
+    offset / distance * max_speed * (distance / slowing_radius).min(1.0)
+}
+
+pub fn flee(position: Vec3, threat: Vec3, max_speed: f32) -> Vec3 {
+    -seek(position, threat, max_speed)
+}
+
+// Random walk, a point on a circle in front of the agent is jittered every tick
+#[derive(Clone, Copy, Debug)]
+pub struct Wander {
+    pub distance: f32,
+    pub radius: f32,
+    // Maximum change of the angle on the circle per tick in radians
+    pub jitter: f32,
+    // Fraction of the speed used while wandering
+    pub speed_factor: f32,
+    angle: f32,
+    seed: u32,
+}
+
+impl Wander {
+    pub fn new(seed: u32) -> Wander {
+        return Wander {
+            distance: 2.0,
+            radius: 1.0,
+            jitter: 0.3,
+            speed_factor: 0.5,
+            angle: 0.0,
+            // xorshift gets stuck on 0
+            seed: seed.max(1),
+        };
+    }
+
+    // Deterministic so replays wander the same way, in -1..1
+    fn next_random(&mut self) -> f32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        (self.seed as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+pub fn wander(transform: &Transform, state: &mut Wander, max_speed: f32) -> Vec3 {
+    state.angle += state.next_random() * state.jitter;
+    let forward = planar(transform.rotation * Vec3::Z).normalize_or_zero();
+    let circle_point = Vec3::new(state.angle.cos(), 0.0, state.angle.sin()) * state.radius;
+    (forward * state.distance + circle_point).normalize_or_zero() * max_speed * state.speed_factor
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Neighbour {
+    pub entity: Entity,
+    pub position: Vec3,
+    pub velocity: Vec3,
+    pub radius: f32,
+}
+
+// Pushes away from neighbours closer than the distance, stronger the closer they are.
+// Result is in 0..1 per neighbour, scale it by the speed
+pub fn separation(position: Vec3, radius: f32, distance: f32, neighbours: &[Neighbour]) -> Vec3 {
+    let mut steer = Vec3::ZERO;
+    for neighbour in neighbours {
+        let offset = planar(position - neighbour.position);
+        let range = distance + radius + neighbour.radius;
+        let d = offset.length();
+        if d >= range {
+            continue;
+        }
+        let away = if d > 0.0 {
+            offset / d
+        } else {
+            // Exactly on top of each other, spread deterministically by entity id
+            let angle = neighbour.entity.id() as f32;
+            Vec3::new(angle.cos(), 0.0, angle.sin())
+        };
+        steer += away * (range - d) / range;
+    }
+    steer
+}
+
+// Reciprocal avoidance of predicted collisions within the time horizon, each agent
+// dodges half so two agents avoiding each other don't overcorrect. Scale it by the speed
+pub fn avoidance(
+    position: Vec3,
+    velocity: Vec3,
+    radius: f32,
+    horizon: f32,
+    neighbours: &[Neighbour],
+) -> Vec3 {
+    let mut steer = Vec3::ZERO;
+    for neighbour in neighbours {
+        let relative_position = planar(neighbour.position - position);
+        let relative_velocity = planar(velocity - neighbour.velocity);
+        let speed_squared = relative_velocity.length_squared();
+        if speed_squared < f32::EPSILON {
+            continue;
+        }
+        let time = relative_position.dot(relative_velocity) / speed_squared;
+        if time <= 0.0 || time > horizon {
+            continue;
+        }
+        let combined_radius = radius + neighbour.radius;
+        // Where the neighbour is relative to us at the closest approach
+        let closest = relative_position - relative_velocity * time;
+        let d = closest.length();
+        if d >= combined_radius {
+            continue;
+        }
+        let away = if d > 0.0 {
+            -closest / d
+        } else {
+            // Head on, both agents pass on their right side
+            relative_velocity.cross(Vec3::Y).normalize_or_zero()
+        };
+        let urgency = 1.0 - time / horizon;
+        steer += away * (combined_radius - d) / combined_radius * urgency * 0.5;
+    }
+    steer
+}
+
+fn planar(v: Vec3) -> Vec3 {
+    Vec3::new(v.x, 0.0, v.z)
+}
+
+#[derive(Component, Clone, Copy, Debug)]
+pub struct SteeringAgent {
+    // Extra gap kept to other characters on top of both radii
+    pub separation_distance: f32,
+    pub separation_weight: f32,
+    // Seconds ahead collisions are predicted
+    pub avoidance_horizon: f32,
+    pub avoidance_weight: f32,
+    // Wanders around while there is no target
+    pub wander: Option<Wander>,
+    // Added to the intent by the character controller, in units of the MovementSpeed
+    pub output: Vec3,
+}
+
+impl Default for SteeringAgent {
+    fn default() -> Self {
+        return Self {
+            separation_distance: 0.2,
+            separation_weight: 1.5,
+            avoidance_horizon: 1.0,
+            avoidance_weight: 1.0,
+            wander: None,
+            output: Vec3::ZERO,
+        };
+    }
+}
+
+// Computes separation, avoidance and wandering on top of the intent of the agents
+pub fn steering_system(
+    characters: Query<(
+        Entity,
+        &Transform,
+        &CharacterCollider,
+        Option<&CharacterController>,
+    )>,
+    mut agents: Query<(
+        Entity,
+        &Transform,
+        &MovementSpeed,
+        &CharacterCollider,
+        &mut SteeringAgent,
+        &MovementIntent,
+        Option<&MovementTarget>,
+    )>,
+) {
+    let all: Vec<Neighbour> = characters
+        .iter()
+        .map(|(entity, transform, collider, controller)| Neighbour {
+            entity,
+            position: transform.translation,
+            velocity: controller.map(|c| c.velocity).unwrap_or_default(),
+            radius: collider.radius,
+        })
+        .collect();
+
+    for (entity, transform, speed, collider, mut agent, intent, target) in agents.iter_mut() {
+        if speed.0 <= 0.0 {
+            continue;
+        }
+        let neighbours: Vec<Neighbour> =
+            all.iter().filter(|n| n.entity != entity).copied().collect();
+        let own = all.iter().find(|n| n.entity == entity);
+        let velocity = own.map(|n| n.velocity).unwrap_or_default();
+        let position = transform.translation;
+
+        let mut desired = Vec3::ZERO;
+        let has_target = target.map(|t| t.current_target.is_some()).unwrap_or(false);
+        if !has_target && intent.direction.length_squared() == 0.0 {
+            if let Some(wander_state) = agent.wander.as_mut() {
+                desired = wander(transform, wander_state, speed.0);
+            }
+        }
+
+        desired += separation(
+            position,
+            collider.radius,
+            agent.separation_distance,
+            &neighbours,
+        ) * agent.separation_weight
+            * speed.0;
+        desired += avoidance(
+            position,
+            velocity,
+            collider.radius,
+            agent.avoidance_horizon,
+            &neighbours,
+        ) * agent.avoidance_weight
+            * speed.0;
+
+        agent.output = desired / speed.0;
+    }
+}
+
+pub struct SteeringPlugin;
+
+impl Plugin for SteeringPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(
+            SimulationStage,
+            steering_system
+                .label(SimulationSystem::STEERING)
+                .after(SimulationSystem::INTENT)
+                .before(SimulationSystem::MOVEMENT),
+        );
+    }
+
+    fn name(&self) -> &str {
+        "SteeringPlugin"
+    }
+}
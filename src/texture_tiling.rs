@@ -1,4 +1,7 @@
+use std::collections::HashMap;
+
 use bevy::{
+    asset::HandleId,
     prelude::*,
     render::{
         mesh::VertexAttributeValues,
@@ -6,104 +9,328 @@ use bevy::{
         texture::ImageSampler,
     },
 };
+use serde::Deserialize;
 // All textures that should be set to address mode REPEAT need to go in this Resource
 pub struct TileableTextures(pub Vec<Handle<Image>>);
 
-#[derive(Component)]
+// UV transform of a single entity: scaled, then rotated around the UV origin, then offset.
+// The entity draws a tiled copy of its mesh, shared by all entities with the same mesh and
+// tiling, so entities sharing a mesh can tile differently
+#[derive(Component, Clone, Debug, Deserialize)]
 pub struct TextureTiling {
     pub x: f32,
     pub y: f32,
+    #[serde(default)]
+    pub offset: Vec2,
+    // Degrees
+    #[serde(default)]
+    pub rotation: f32,
+}
+
+impl TextureTiling {
+    pub fn transform_uv(&self, uv: [f32; 2]) -> [f32; 2] {
+        let scaled = Vec2::new(uv[0] * self.x, uv[1] * self.y);
+        (Mat2::from_angle(self.rotation.to_radians()) * scaled + self.offset).to_array()
+    }
+
+    fn key(&self, source: HandleId) -> TilingKey {
+        return TilingKey {
+            source,
+            scale: [self.x.to_bits(), self.y.to_bits()],
+            offset: [self.offset.x.to_bits(), self.offset.y.to_bits()],
+            rotation: self.rotation.to_bits(),
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct TilingKey {
+    source: HandleId,
+    scale: [u32; 2],
+    offset: [u32; 2],
+    rotation: u32,
 }
 
+// Tiled copies by source mesh and tiling. Weak, so a copy is freed with its last entity
+#[derive(Default)]
+struct TiledMeshCache(HashMap<TilingKey, Handle<Mesh>>);
+
+// The untouched shared mesh the tiled copy of the entity is made from, put back when the
+// tiling is removed
+#[derive(Component)]
+pub struct TiledMesh {
+    pub source: Handle<Mesh>,
+}
+
+fn tiled_copy(
+    entity: Entity,
+    source: &Handle<Mesh>,
+    mesh: &Mesh,
+    tiling: &TextureTiling,
+) -> Option<Mesh> {
+    let mut mesh = mesh.clone();
+    match mesh.attribute_mut(Mesh::ATTRIBUTE_UV_0) {
+        Some(VertexAttributeValues::Float32x2(uvs)) => {
+            for uv in uvs {
+                *uv = tiling.transform_uv(*uv);
+            }
+        }
+        Some(_) => {
+            warn!(
+                "Skipped tiling of {:?}, mesh {:?} has UVs that aren't Float32x2",
+                entity, source.id
+            );
+            return None;
+        }
+        None => {
+            warn!(
+                "Skipped tiling of {:?}, mesh {:?} has no UVs",
+                entity, source.id
+            );
+            return None;
+        }
+    }
+    // Tangents copied from the source don't match rotated UVs
+    if mesh.attribute(Mesh::ATTRIBUTE_TANGENT).is_some() {
+        if let Err(e) = mesh.generate_tangents() {
+            warn!(
+                "Failed to generate tangents of the tiled copy of mesh {:?} used by {:?}: {}",
+                source.id, entity, e
+            );
+        }
+    }
+    Some(mesh)
+}
+
+// Points the entity at the tiled copy when the tiling changes or the source mesh is (re)loaded
 fn tiling_system(
+    mut commands: Commands,
     mut ev_asset: EventReader<AssetEvent<Mesh>>,
-    mut query: Query<(&TextureTiling, &mut Handle<Mesh>)>,
+    mut cache: ResMut<TiledMeshCache>,
+    mut query: Query<(
+        Entity,
+        &TextureTiling,
+        ChangeTrackers<TextureTiling>,
+        &mut Handle<Mesh>,
+        Option<&TiledMesh>,
+    )>,
     mut meshes: ResMut<Assets<Mesh>>,
 ) {
+    let mut loaded: Vec<Handle<Mesh>> = Vec::new();
     for ev in ev_asset.iter() {
         match ev {
-            AssetEvent::Created { handle } => {
-                let mesh = meshes
-                    .get_mut(&handle)
-                    .expect("falied to get loaded mesh asset");
-
-                mesh.generate_tangents()
-                    .expect("Failed to generate tangents");
-
-                for (tiling_info, mesh_handle) in query.iter_mut() {
-                    if *mesh_handle == *handle {
-                        let mesh = meshes
-                            .get_mut(&mesh_handle)
-                            .expect("falied to get loaded mesh asset");
-
-                        if let Some(VertexAttributeValues::Float32x2(uvs)) =
-                            mesh.attribute_mut(Mesh::ATTRIBUTE_UV_0)
-                        {
-                            for uv in uvs {
-                                uv[0] *= tiling_info.x;
-                                uv[1] *= tiling_info.y;
-                            }
-
-                            println!("modified uv's for mesh")
-                        }
-                    }
-                }
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                // Copies of a changed source are outdated
+                cache.0.retain(|key, _| key.source != handle.id);
+                loaded.push(handle.clone_weak());
+            }
+            AssetEvent::Removed { handle } => cache.0.retain(|_, copy| *copy != *handle),
+        }
+    }
+
+    for (entity, tiling, tiling_tracker, mut mesh_handle, tiled_mesh) in query.iter_mut() {
+        let source = match tiled_mesh {
+            Some(tiled_mesh) => tiled_mesh.source.clone(),
+            None => mesh_handle.clone(),
+        };
+        if !tiling_tracker.is_changed() && !loaded.contains(&source) {
+            continue;
+        }
+
+        let key = tiling.key(source.id);
+        let cached = cache
+            .0
+            .get(&key)
+            .filter(|copy| meshes.get(*copy).is_some())
+            .map(|copy| meshes.get_handle(copy.id));
+        let copy = match cached {
+            Some(copy) => copy,
+            None => {
+                // Not loaded yet, retried on its Created event
+                let mesh = match meshes.get(&source) {
+                    Some(mesh) => mesh,
+                    None => continue,
+                };
+                let mesh = match tiled_copy(entity, &source, mesh, tiling) {
+                    Some(mesh) => mesh,
+                    None => continue,
+                };
+                let copy = meshes.add(mesh);
+                cache.0.insert(key, copy.clone_weak());
+                copy
+            }
+        };
+
+        if *mesh_handle != copy {
+            *mesh_handle = copy;
+        }
+        if tiled_mesh.is_none() {
+            commands.entity(entity).insert(TiledMesh { source });
+        }
+    }
+}
+
+// Puts the untouched mesh back on entities that lost their tiling
+fn tiling_removed_system(
+    mut commands: Commands,
+    removed: RemovedComponents<TextureTiling>,
+    mut query: Query<(&TiledMesh, &mut Handle<Mesh>), Without<TextureTiling>>,
+) {
+    for entity in removed.iter() {
+        if let Ok((tiled_mesh, mut mesh_handle)) = query.get_mut(entity) {
+            *mesh_handle = tiled_mesh.source.clone();
+            commands.entity(entity).remove::<TiledMesh>();
+        }
+    }
+}
+
+// Normal maps need tangents, only meshes drawn with one get them
+fn tangent_system(
+    mut ev_asset: EventReader<AssetEvent<Mesh>>,
+    query: Query<(
+        Entity,
+        &Handle<Mesh>,
+        &Handle<StandardMaterial>,
+        ChangeTrackers<Handle<Mesh>>,
+        ChangeTrackers<Handle<StandardMaterial>>,
+    )>,
+    materials: Res<Assets<StandardMaterial>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    let created: Vec<Handle<Mesh>> = ev_asset
+        .iter()
+        .filter_map(|ev| match ev {
+            AssetEvent::Created { handle } => Some(handle.clone_weak()),
+            _ => None,
+        })
+        .collect();
+
+    for (entity, mesh_handle, material_handle, mesh_tracker, material_tracker) in query.iter() {
+        // Meshes that aren't loaded yet are handled by their Created event
+        if !mesh_tracker.is_changed()
+            && !material_tracker.is_changed()
+            && !created.contains(mesh_handle)
+        {
+            continue;
+        }
+        let uses_normal_map = materials
+            .get(material_handle)
+            .map_or(false, |material| material.normal_map_texture.is_some());
+        if !uses_normal_map {
+            continue;
+        }
+        // Checked before get_mut, which would mark the mesh as modified
+        match meshes.get(mesh_handle) {
+            Some(mesh) if mesh.attribute(Mesh::ATTRIBUTE_TANGENT).is_none() => {}
+            _ => continue,
+        }
+
+        if let Some(mesh) = meshes.get_mut(mesh_handle) {
+            if let Err(e) = mesh.generate_tangents() {
+                warn!(
+                    "Failed to generate tangents of mesh {:?} used by {:?}: {}",
+                    mesh_handle.id, entity, e
+                );
             }
-            AssetEvent::Modified { handle } => (),
-            AssetEvent::Removed { handle } => (),
         }
     }
 }
 
+fn repeat_sampler() -> ImageSampler {
+    ImageSampler::Descriptor(SamplerDescriptor {
+        mag_filter: FilterMode::Linear,
+        min_filter: FilterMode::Linear,
+        mipmap_filter: FilterMode::Linear,
+        address_mode_u: AddressMode::Repeat,
+        address_mode_v: AddressMode::Repeat,
+        ..Default::default()
+    })
+}
+
+fn uses_image(material: &StandardMaterial, image: &Handle<Image>) -> bool {
+    [
+        &material.base_color_texture,
+        &material.emissive_texture,
+        &material.metallic_roughness_texture,
+        &material.normal_map_texture,
+        &material.occlusion_texture,
+    ]
+    .iter()
+    .any(|texture| texture.as_ref() == Some(image))
+}
+
 fn image_config_system(
     mut ev_asset: EventReader<AssetEvent<Image>>,
     mut images: ResMut<Assets<Image>>,
     textures: Res<TileableTextures>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
+    let mut configured: Vec<Handle<Image>> = Vec::new();
+
+    // Textures preloaded by the asset manifest were created before the level marked them
+    // as tileable
+    if textures.is_changed() {
+        for handle in textures.0.iter() {
+            match images.get_mut(handle) {
+                Some(image) => {
+                    image.sampler_descriptor = repeat_sampler();
+                    configured.push(handle.clone_weak());
+                }
+                None => info!(
+                    "Image {:?} isn't loaded yet, its repeat sampler is set once it is",
+                    handle.id
+                ),
+            }
+        }
+    }
+
     for ev in ev_asset.iter() {
         match ev {
             AssetEvent::Created { handle } => {
                 // a texture was just loaded or changed!
+                if !textures.0.contains(handle) {
+                    continue;
+                }
 
                 // WARNING: this mutable access will cause another
                 // AssetEvent (Modified) to be emitted!
-                let image = images.get_mut(handle).unwrap();
-                // ^ unwrap is OK, because we know it is loaded now
-
-                if textures.0.contains(&*handle) {
-                    // It's a tileable texture
-                    image.sampler_descriptor = ImageSampler::Descriptor(SamplerDescriptor {
-                        mag_filter: FilterMode::Linear,
-                        min_filter: FilterMode::Linear,
-                        mipmap_filter: FilterMode::Linear,
-                        address_mode_u: AddressMode::Repeat,
-                        address_mode_v: AddressMode::Repeat,
-                        ..Default::default()
-                    });
-                    println!("Ran image_config_system");
-                    for (_, mut mat) in materials.iter_mut() {
-                        // Weird hack needed to force material to update TODO: Find better way
-                        let col = mat.base_color;
-                        mat.base_color = col; 
+                match images.get_mut(handle) {
+                    Some(image) => {
+                        // It's a tileable texture
+                        image.sampler_descriptor = repeat_sampler();
+                        configured.push(handle.clone_weak());
                     }
+                    None => warn!(
+                        "Skipped repeat sampler of image {:?}, it was removed again",
+                        handle.id
+                    ),
                 }
             }
-            AssetEvent::Modified { handle } => {
-                // an image was modified
-            }
-            AssetEvent::Removed { handle } => {
-                // an image was unloaded
-            }
+            AssetEvent::Modified { .. } => (),
+            AssetEvent::Removed { .. } => (),
         }
     }
+
+    // Materials only pick up a new sampler when they change themselves
+    let stale: Vec<HandleId> = materials
+        .iter()
+        .filter(|(_, material)| configured.iter().any(|image| uses_image(material, image)))
+        .map(|(id, _)| id)
+        .collect();
+    for id in stale {
+        // The mutable access alone marks the material as modified
+        materials.get_mut(id);
+    }
 }
 
 pub struct TextureTilingPlugin;
 
 impl Plugin for TextureTilingPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(tiling_system)
+        app.init_resource::<TiledMeshCache>()
+            .add_system(tiling_system)
+            .add_system_to_stage(CoreStage::PostUpdate, tiling_removed_system)
+            .add_system(tangent_system.after(tiling_system))
             .add_system(image_config_system);
     }
 